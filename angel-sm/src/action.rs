@@ -1,13 +1,19 @@
 use crate::AngelJob;
+use color_eyre::eyre::{WrapErr, eyre};
 use crate::pfunc::ProcessFunction;
-use crate::util::{vec_or_single, deser_duration};
-use cthulhu_common::devinfo::DeviceInformation;
-use serde::Deserialize;
+use crate::script::run_script;
+use crate::textfsm::Template;
+use crate::template::{render_public_template, render_template};
+use crate::util::{vec_or_single, deser_duration, ser_duration};
+use cthulhu_common::devinfo::{DeviceInformation, DeviceInformationKind};
+use regex::{Captures, Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::time::Duration;
 use swexpect::SwitchExpect;
 use tracing::warn;
 
-#[derive(Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
 #[serde(untagged)]
 pub enum DeviceInfoArg {
     WithArgument(DeviceInformation),
@@ -23,13 +29,35 @@ impl From<DeviceInfoArg> for DeviceInformation {
     }
 }
 
-#[derive(Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+/// Which text a [`Action::Capture`] regex is matched against.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialOrd, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureSource {
+    /// Everything received before the trigger matched.
+    #[default]
+    Data,
+    /// The text that matched the trigger.
+    Match,
+}
+
+impl CaptureSource {
+    fn select<'a>(&self, data: &'a str, mat: &'a str) -> &'a str {
+        match self {
+            CaptureSource::Data => data,
+            CaptureSource::Match => mat,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
 #[serde(tag = "type")]
 pub enum Action {
+    /// Send text, filling in `{{config.key}}` and `{{info.Kind}}` variables.
     Send {
         text: String,
     },
     Flush,
+    /// Send a line, filling in variables like [`Action::Send`].
     SendLine {
         line: String,
     },
@@ -45,7 +73,7 @@ pub enum Action {
         times: usize,
     },
     Delay {
-        #[serde(deserialize_with = "deser_duration")]
+        #[serde(deserialize_with = "deser_duration", serialize_with = "ser_duration")]
         duration: Duration,
     },
     AddDeviceInfo(DeviceInfoArg),
@@ -54,9 +82,145 @@ pub enum Action {
     SendConfigValue {
         key: String,
     },
+    /// Match a regex and record its named groups as device information. Groups named
+    /// after a [`DeviceInformationKind`] are recorded as that kind, others need an entry in `groups`.
+    Capture {
+        regex: String,
+        #[serde(default)]
+        source: CaptureSource,
+        #[serde(default)]
+        groups: BTreeMap<String, DeviceInformationKind>,
+        /// Only record the first match of the regex.
+        #[serde(default)]
+        first: bool,
+    },
+    /// Set a job variable, filling in variables like [`Action::Send`].
+    SetVariable {
+        name: String,
+        value: String,
+    },
+    /// Add `by` to a numeric job variable. A variable that is not set counts as 0.
+    IncrementVariable {
+        name: String,
+        #[serde(default = "one")]
+        by: i64,
+    },
+    /// Match a regex and set a job variable for each of its named groups.
+    CaptureVariable {
+        regex: String,
+        #[serde(default)]
+        source: CaptureSource,
+        /// Only use the first match of the regex.
+        #[serde(default)]
+        first: bool,
+    },
+    /// Perform `actions` once for every match of a regex, with its named groups set as job variables.
+    ForEach {
+        regex: String,
+        #[serde(default)]
+        source: CaptureSource,
+        #[serde(deserialize_with = "vec_or_single", rename = "action")]
+        actions: Vec<Action>,
+    },
+    /// Wait until the device sends `string`.
+    WaitFor {
+        string: String,
+    },
+    /// Parse a table with a TextFSM template shipped next to the state files, see [`crate::textfsm`].
+    ParseTable {
+        template: String,
+        #[serde(default)]
+        source: CaptureSource,
+        /// Columns to record as device information.
+        #[serde(default)]
+        columns: BTreeMap<String, DeviceInformationKind>,
+        /// Only use rows whose columns match these regexes.
+        #[serde(default)]
+        filter: BTreeMap<String, String>,
+        /// Record every row as an inventory item.
+        #[serde(default)]
+        inventory: bool,
+        /// Filled in from the loaded templates when the state machine is built.
+        #[serde(skip)]
+        template_source: String,
+    },
+    /// Run a Rhai script shipped next to the state files, see [`crate::script`].
+    Script {
+        script: String,
+        /// Filled in from the loaded scripts when the state machine is built.
+        #[serde(skip)]
+        source: String,
+    },
+}
+
+/// How long [`Action::WaitFor`] and `wait_for` in scripts wait for the device.
+pub const WAIT_FOR_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+fn one() -> i64 {
+    1
+}
+
+/// Build a regex the way captures are matched: multi-line, with `\r\n` line endings.
+pub fn capture_regex(regex: &str) -> color_eyre::Result<Regex> {
+    Ok(RegexBuilder::new(regex)
+        .multi_line(true)
+        .crlf(true)
+        .build()?)
+}
+
+pub(crate) fn capture_group_kind(
+    name: &str,
+    groups: &BTreeMap<String, DeviceInformationKind>,
+) -> color_eyre::Result<DeviceInformationKind> {
+    match groups.get(name) {
+        Some(kind) => Ok(*kind),
+        None => name
+            .parse()
+            .map_err(|e| eyre!("capture group {name} has no information kind: {e}")),
+    }
+}
+
+async fn set_capture_variables<T: AngelJob>(
+    job: &mut T,
+    r: &Regex,
+    cap: &Captures<'_>,
+) -> color_eyre::Result<()> {
+    for name in r.capture_names().flatten() {
+        if let Some(v) = cap.name(name) {
+            job.set_variable(name, v.as_str().to_string()).await?;
+        }
+    }
+    Ok(())
 }
 
 impl Action {
+    /// Add the kinds of device information this action can record. Scripts are not looked into.
+    pub(crate) fn recorded_kinds(&self, kinds: &mut BTreeSet<DeviceInformationKind>) {
+        match self {
+            Action::AddDeviceInfo(arg) => {
+                if let Some((kind, _)) = DeviceInformation::from(arg.clone()).value() {
+                    kinds.insert(kind);
+                }
+            }
+            Action::Capture { regex, groups, .. } => {
+                if let Ok(r) = capture_regex(regex) {
+                    kinds.extend(
+                        r.capture_names()
+                            .flatten()
+                            .filter_map(|name| capture_group_kind(name, groups).ok()),
+                    );
+                }
+            }
+            Action::ParseTable { columns, .. } => kinds.extend(columns.values()),
+            Action::Repeat { actions, .. } | Action::ForEach { actions, .. } => {
+                for action in actions.iter() {
+                    action.recorded_kinds(kinds);
+                }
+            }
+            _ => {}
+        }
+    }
+
     pub async fn perform<T: AngelJob>(
         &self,
         job: &mut T,
@@ -66,7 +230,9 @@ impl Action {
     ) -> color_eyre::Result<()> {
         match self {
             Action::Send { text: s } => {
-                p.send(s).await?;
+                let text = render_template(job, s).await?;
+                p.send(&text).await?;
+                job.record_sent_line(&text).await;
                 Ok(())
             }
             Action::Flush => {
@@ -74,7 +240,9 @@ impl Action {
                 Ok(())
             }
             Action::SendLine { line: s } => {
-                p.send_line(s).await?;
+                let line = render_template(job, s).await?;
+                p.send_line(&line).await?;
+                job.record_sent_line(&line).await;
                 Ok(())
             }
             Action::SendControl { char: c } => {
@@ -112,11 +280,118 @@ impl Action {
             Action::SendConfigValue { key } => {
                 if let Some(v) = job.get_job_config_key(key).await {
                     p.send(&v).await?;
+                    job.record_sent_line(&v).await;
                 } else {
                     warn!("No such config item: {key}");
                 }
                 Ok(())
             }
+            Action::Capture {
+                regex,
+                source,
+                groups,
+                first,
+            } => {
+                let r = capture_regex(regex)?;
+                let kinds = r
+                    .capture_names()
+                    .flatten()
+                    .map(|n| Ok((n, capture_group_kind(n, groups)?)))
+                    .collect::<color_eyre::Result<Vec<_>>>()?;
+                for cap in r.captures_iter(source.select(data, mat)) {
+                    for (name, kind) in kinds.iter() {
+                        if let Some(v) = cap.name(name) {
+                            job.add_information(kind.with_value(v.as_str().to_string()))
+                                .await?;
+                        }
+                    }
+                    if *first {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Action::SetVariable { name, value } => {
+                let value = render_public_template(job, value).await?;
+                job.set_variable(name, value).await
+            }
+            Action::IncrementVariable { name, by } => {
+                let current = match job.get_variables().await.get(name) {
+                    Some(v) => v
+                        .parse::<i64>()
+                        .map_err(|e| eyre!("variable {name} is not a number: {e}"))?,
+                    None => 0,
+                };
+                job.set_variable(name, (current + by).to_string()).await
+            }
+            Action::CaptureVariable {
+                regex,
+                source,
+                first,
+            } => {
+                let r = capture_regex(regex)?;
+                for cap in r.captures_iter(source.select(data, mat)) {
+                    set_capture_variables(job, &r, &cap).await?;
+                    if *first {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Action::ForEach {
+                regex,
+                source,
+                actions,
+            } => {
+                let r = capture_regex(regex)?;
+                for cap in r.captures_iter(source.select(data, mat)) {
+                    set_capture_variables(job, &r, &cap).await?;
+                    for a in actions.iter() {
+                        Box::pin(a.perform(job, p, data, mat)).await?;
+                    }
+                }
+                Ok(())
+            }
+            Action::WaitFor { string } => {
+                tokio::time::timeout(WAIT_FOR_TIMEOUT, p.exp_string(string))
+                    .await
+                    .map_err(|_| eyre!("{string:?} was not received within {WAIT_FOR_TIMEOUT:?}"))??;
+                Ok(())
+            }
+            Action::ParseTable {
+                template,
+                source,
+                columns,
+                filter,
+                inventory,
+                template_source,
+            } => {
+                let table = Template::parse(template_source)
+                    .and_then(|t| t.parse_text(source.select(data, mat)))
+                    .wrap_err_with(|| format!("unable to parse table with template {template}"))?;
+                let filter = filter
+                    .iter()
+                    .map(|(column, regex)| Ok((column, Regex::new(regex)?)))
+                    .collect::<color_eyre::Result<Vec<_>>>()?;
+                for record in table.records() {
+                    let matches = filter
+                        .iter()
+                        .all(|(column, r)| record.get(*column).is_some_and(|v| r.is_match(v)));
+                    if !matches {
+                        continue;
+                    }
+                    for (column, kind) in columns.iter() {
+                        if let Some(v) = record.get(column) {
+                            job.add_information(kind.with_value(v.clone())).await?;
+                        }
+                    }
+                    if *inventory {
+                        job.add_information(DeviceInformation::InventoryItem(record)).await?;
+                    }
+                }
+                Ok(())
+            }
+            Action::Script { script, source } => run_script(script, source, job, p, data, mat).await,
         }
     }
 }
@@ -1,15 +1,32 @@
 use std::io::Write;
 use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::eyre;
+use cthulhu_angel_sm::action::Action;
 use cthulhu_angel_sm::builder::StateMachineBuilder;
+use cthulhu_angel_sm::data_structure::{State, StateMachineTrigger};
+use cthulhu_angel_sm::lint::{LintConfig, LintSeverity};
+use cthulhu_angel_sm::replay::replay_log;
+use cthulhu_angel_sm::runner::{END_STATE, FINISHED_STATE, INITIAL_STATE};
+use cthulhu_angel_sm::state::StateMachine;
+use cthulhu_common::devinfo::{DeviceInformation, DeviceInformationType};
+use cthulhu_common::job::JobData;
+use cthulhu_config::angel::AngelConfig;
 use graphviz_rust::cmd::Format;
 use graphviz_rust::dot_generator::*;
 use graphviz_rust::dot_structures::*;
 use graphviz_rust::exec_dot;
 use graphviz_rust::printer::{DotPrinter, PrinterContext};
-use std::path::PathBuf;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Debug, Parser)]
 struct Args {
+    /// Extra directories to load state files from.
+    #[clap(long, global = true)]
+    state_dir: Vec<PathBuf>,
     #[clap(subcommand)]
     command: CliCmd,
 }
@@ -22,8 +39,43 @@ enum CliCmd {
         format: CliFormat,
         #[clap(long, short)]
         output: Option<PathBuf>,
+        /// Highlight the path a job took, from heaven's `job.json` or an angel log.
+        #[clap(long)]
+        history: Option<PathBuf>,
         state: String,
     },
+    /// Check the merged state machine for problems.
+    Lint {
+        /// Angel config to take the active states, state directories and job config from.
+        #[clap(long, short)]
+        config: Option<PathBuf>,
+        /// State files to activate, defaults to the active states of the angel config.
+        states: Vec<String>,
+    },
+    /// Replay recorded raw serial logs through the state machine.
+    Replay {
+        /// Angel config to take the active states, state directories and job config from.
+        #[clap(long, short)]
+        config: Option<PathBuf>,
+        /// State files to activate, defaults to the active states of the angel config.
+        #[clap(long, short)]
+        state: Vec<String>,
+        /// Raw serial logs (`*.raw.log`) to replay.
+        #[clap(required = true)]
+        logs: Vec<PathBuf>,
+    },
+    /// Dump the fully merged state machine, as the angel runs it.
+    Export {
+        /// Angel config to take the active states and state directories from.
+        #[clap(long, short)]
+        config: Option<PathBuf>,
+        #[clap(long, short, value_enum, default_value = "json")]
+        format: ExportFormat,
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+        /// State files to activate, defaults to the active states of the angel config.
+        states: Vec<String>,
+    },
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -33,34 +85,308 @@ enum CliFormat {
     Png,
 }
 
+#[derive(Debug, Clone, ValueEnum)]
+enum ExportFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// A built state machine with what it was built from, as written by `export`.
+#[derive(Serialize)]
+struct ExportedStateMachine<'a> {
+    fingerprint: &'a str,
+    active_files: &'a [String],
+    /// Sources of the scripts used by actions, by name.
+    scripts: BTreeMap<String, String>,
+    /// Sources of the table templates used by actions, by name.
+    templates: BTreeMap<String, String>,
+    state_machine: &'a StateMachine,
+}
+
+fn load_builder(state_dirs: &[PathBuf]) -> color_eyre::Result<StateMachineBuilder> {
+    let mut builder = StateMachineBuilder::new();
+    builder.load_builtin_state_files()?;
+    for dir in state_dirs {
+        builder.load_state_files_from_dir(dir)?;
+    }
+    Ok(builder)
+}
+
+fn load_config(config: Option<PathBuf>) -> color_eyre::Result<Option<AngelConfig>> {
+    config
+        .map(|c| -> color_eyre::Result<AngelConfig> {
+            Ok(toml::from_str(&std::fs::read_to_string(c)?)?)
+        })
+        .transpose()
+}
+
+/// Load and activate state files, taking defaults from the angel config if given.
+fn load_active_builder(
+    state_dirs: &[PathBuf],
+    config: Option<&AngelConfig>,
+    states: Vec<String>,
+) -> color_eyre::Result<StateMachineBuilder> {
+    let mut state_dirs = state_dirs.to_vec();
+    let mut states = states;
+    if let Some(config) = config {
+        state_dirs.extend(config.state_dirs.iter().cloned());
+        if states.is_empty() {
+            states = config.active_states.clone();
+        }
+    }
+    if states.is_empty() {
+        return Err(eyre!("no state files to activate"));
+    }
+
+    let mut builder = load_builder(&state_dirs)?;
+    for id in states.iter() {
+        builder.activate_state_file(id)?;
+    }
+    Ok(builder)
+}
+
+/// Longest label line drawn before it is cut off.
+const MAX_LABEL: usize = 40;
+
+fn shorten(s: &str) -> String {
+    if s.chars().count() > MAX_LABEL {
+        format!("{}...", s.chars().take(MAX_LABEL - 3).collect::<String>())
+    } else {
+        s.to_string()
+    }
+}
+
+/// Quote a string as a DOT id.
+fn quoted(s: &str) -> Id {
+    id!(esc s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quote label lines, joined by DOT line breaks.
+fn label(lines: &[String]) -> Id {
+    let lines: Vec<_> = lines
+        .iter()
+        .map(|l| shorten(l).replace('\\', "\\\\").replace('"', "\\\""))
+        .collect();
+    id!(esc lines.join("\\n"))
+}
+
+fn trigger_label(t: &StateMachineTrigger) -> String {
+    match t {
+        StateMachineTrigger::String { string, .. } => format!("{string:?}"),
+        StateMachineTrigger::Regex { regex, .. } => format!("/{regex}/"),
+        StateMachineTrigger::Immediate => "immediate".to_string(),
+        StateMachineTrigger::Timeout { duration } => format!("timeout {duration:?}"),
+        StateMachineTrigger::AllOf { triggers, window } => format!(
+            "all of {} within {window:?}",
+            triggers.iter().map(trigger_label).collect::<Vec<_>>().join(", ")
+        ),
+        StateMachineTrigger::Sequence { triggers, window } => format!(
+            "{} within {window:?}",
+            triggers.iter().map(trigger_label).collect::<Vec<_>>().join(" then ")
+        ),
+        StateMachineTrigger::NotWithin { trigger, not, window } => format!(
+            "{} without {} within {window:?}",
+            trigger_label(trigger),
+            trigger_label(not)
+        ),
+    }
+}
+
+fn action_label(a: &Action) -> String {
+    match a {
+        Action::Send { text } => format!("send {text:?}"),
+        Action::Flush => "flush".to_string(),
+        Action::SendLine { line } => format!("send {line:?}"),
+        Action::SendControl { char } => format!("^{}", char.to_ascii_uppercase()),
+        Action::Function { func } => format!("{func:?}"),
+        Action::Repeat { actions, times } => format!("{times}x ({})", actions_label(actions)),
+        Action::Delay { duration } => format!("delay {duration:?}"),
+        Action::AddDeviceInfo(i) => format!("+{}", DeviceInformation::from(i.clone())),
+        Action::FinishJob => "finish job".to_string(),
+        Action::SetupJob => "setup job".to_string(),
+        Action::SendConfigValue { key } => format!("send config.{key}"),
+        Action::Capture { .. } => "capture".to_string(),
+        Action::SetVariable { name, value } => format!("{name} = {value:?}"),
+        Action::IncrementVariable { name, by } => format!("{name} += {by}"),
+        Action::CaptureVariable { .. } => "capture variables".to_string(),
+        Action::ForEach { actions, .. } => format!("for each ({})", actions_label(actions)),
+        Action::WaitFor { string } => format!("wait for {string:?}"),
+        Action::ParseTable { template, .. } => format!("parse table {template}"),
+        Action::Script { script, .. } => format!("script {script}"),
+    }
+}
+
+fn actions_label(actions: &[Action]) -> String {
+    actions.iter().map(action_label).collect::<Vec<_>>().join(", ")
+}
+
+/// Whether any of the actions records an error flag.
+fn sets_error_flag(actions: &[Action]) -> bool {
+    actions.iter().any(|a| match a {
+        Action::AddDeviceInfo(i) => {
+            DeviceInformation::from(i.clone()).get_type() == DeviceInformationType::Error
+        }
+        Action::Repeat { actions, .. } | Action::ForEach { actions, .. } => sets_error_flag(actions),
+        _ => false,
+    })
+}
+
+/// Read the states a job went through, either from heaven's JSON job data or from
+/// the state transitions in an angel log. A log only keeps the last job in it.
+fn load_history(path: &Path) -> color_eyre::Result<Vec<State>> {
+    let contents = std::fs::read_to_string(path)?;
+    if let Ok(data) = serde_json::from_str::<JobData>(&contents) {
+        return Ok(data.state_history.into_iter().map(|(_, s)| s).collect());
+    }
+
+    let r = Regex::new(r#"State transition: "([^"]+)" -> "([^"]+)""#)?;
+    let mut history = Vec::new();
+    for cap in r.captures_iter(&contents) {
+        let (from, to) = (&cap[1], &cap[2]);
+        if from == INITIAL_STATE || history.is_empty() {
+            history = vec![from.to_string()];
+        }
+        history.push(to.to_string());
+    }
+    if history.is_empty() {
+        return Err(eyre!("no state history found in {}", path.display()));
+    }
+    Ok(history)
+}
+
+/// Draw a state machine, with states clustered by the state file they come from,
+/// and the path of `history` highlighted.
+fn state_graph(sm: &StateMachine, name: &str, history: &[State]) -> color_eyre::Result<Graph> {
+    let visited: BTreeSet<&str> = history.iter().map(|s| s.as_str()).collect();
+    let taken: BTreeSet<(&str, &str)> = history
+        .windows(2)
+        .map(|w| (w[0].as_str(), w[1].as_str()))
+        .collect();
+
+    let mut g: Graph = graph!(di quoted(name));
+    let mut clusters: BTreeMap<Option<&str>, Vec<Stmt>> = BTreeMap::new();
+    let mut edges = Vec::new();
+    let mut edge = |from: &str, to: &str, mut attributes: Vec<Attribute>| {
+        if taken.contains(&(from, to)) {
+            attributes.push(attr!("color", "blue"));
+            attributes.push(attr!("penwidth", 2));
+        }
+        edges.push(Stmt::from(Edge {
+            ty: EdgeTy::Pair(Vertex::N(NodeId(quoted(from), None)), Vertex::N(NodeId(quoted(to), None))),
+            attributes,
+        }));
+    };
+
+    for n in sm.states() {
+        let s = sm.state(&n)?;
+        let terminal = n == END_STATE
+            || n == FINISHED_STATE
+            || s.transitions.iter().all(|t| t.target == n);
+        let loops = s.transitions.iter().any(|t| t.target == n);
+        let error = sets_error_flag(&s.on_enter)
+            || sets_error_flag(&s.on_exit)
+            || s.transitions.iter().any(|t| sets_error_flag(&t.actions));
+
+        let mut attributes = vec![attr!("style", "filled")];
+        if error {
+            attributes.push(attr!("fillcolor", esc "#f4cccc"));
+        } else if terminal {
+            attributes.push(attr!("fillcolor", esc "#d9d9d9"));
+        } else if loops {
+            attributes.push(attr!("fillcolor", esc "#fff2cc"));
+        } else {
+            attributes.push(attr!("fillcolor", "white"));
+        }
+        if visited.contains(n.as_str()) {
+            attributes.push(attr!("color", "blue"));
+            attributes.push(attr!("penwidth", 3));
+        }
+        clusters
+            .entry(sm.origin(&n))
+            .or_default()
+            .push(Node::new(NodeId(quoted(&n), None), attributes).into());
+
+        for t in s.transitions.iter() {
+            let mut lines = vec![trigger_label(&t.trigger)];
+            if !t.guards.is_empty() {
+                lines.push(format!("{} guards", t.guards.len()));
+            }
+            if !t.actions.is_empty() {
+                lines.push(actions_label(&t.actions));
+            }
+            edge(&n, &t.target, vec![Attribute(id!("label"), label(&lines))]);
+        }
+        if let Some(limit) = &s.limit {
+            edge(
+                &n,
+                &limit.target,
+                vec![
+                    Attribute(id!("label"), label(&[format!("after {} visits", limit.max_visits)])),
+                    attr!("style", "dashed"),
+                ],
+            );
+        }
+    }
+    for (watcher_name, w) in sm.watchers() {
+        let Some(target) = &w.target else {
+            continue;
+        };
+        for n in sm.states() {
+            if sm.origin(&n).is_some_and(|o| w.scope.iter().any(|s| s == o)) {
+                edge(
+                    &n,
+                    target,
+                    vec![
+                        Attribute(id!("label"), label(&[format!("watcher {watcher_name}")])),
+                        attr!("style", "dashed"),
+                    ],
+                );
+            }
+        }
+    }
+
+    for (origin, stmts) in clusters {
+        match origin {
+            Some(origin) => {
+                let mut cluster = Subgraph {
+                    id: quoted(&format!("cluster_{origin}")),
+                    stmts: vec![Attribute(id!("label"), quoted(origin)).into()],
+                };
+                cluster.stmts.extend(stmts);
+                g.add_stmt(cluster.into());
+            }
+            None => stmts.into_iter().for_each(|s| g.add_stmt(s)),
+        }
+    }
+    edges.into_iter().for_each(|e| g.add_stmt(e));
+    Ok(g)
+}
+
 fn main() -> color_eyre::Result<()> {
-    tracing_subscriber::fmt::init();
+    // Logs go to stderr, so that exports and graphs can be piped.
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
 
     let args = Args::parse();
 
     match args.command {
         CliCmd::List => {
-            let mut builder = StateMachineBuilder::new();
-            builder.load_builtin_state_files()?;
+            let builder = load_builder(&args.state_dir)?;
             for id in builder.loaded_state_file_ids() {
                 println!("{id}");
             }
         }
-        CliCmd::Graph { format, output, state } => {
-            let mut builder = StateMachineBuilder::new();
-            builder.load_builtin_state_files()?;
+        CliCmd::Graph {
+            format,
+            output,
+            history,
+            state,
+        } => {
+            let mut builder = load_builder(&args.state_dir)?;
             builder.activate_state_file(&state)?;
             let sm = builder.build()?;
-            let mut g: Graph = graph!(strict di id!(&state));
-            for n in sm.states() {
-                g.add_stmt(node!(n).into());
-            }
-            for n in sm.states() {
-                let s = sm.state(&n)?;
-                for t in s.transitions {
-                    g.add_stmt(edge!(node_id!(&n) => node_id!(&t.target)).into());
-                }
-            }
+            let history = history.map(|h| load_history(&h)).transpose()?.unwrap_or_default();
+            let g = state_graph(&sm, &state, &history)?;
             let dot = g.print(&mut PrinterContext::default());
             let data = match format {
                 CliFormat::Dot => dot.into_bytes(),
@@ -81,6 +407,86 @@ fn main() -> color_eyre::Result<()> {
                 }
             }
         }
+        CliCmd::Lint { config, states } => {
+            let config = load_config(config)?;
+            let mut builder = load_active_builder(&args.state_dir, config.as_ref(), states)?;
+            let secrets: Option<BTreeSet<_>> =
+                config.as_ref().map(|c| c.secrets.keys().cloned().collect());
+            let issues = builder.lint(config.as_ref().zip(secrets.as_ref()).map(
+                |(c, secrets)| LintConfig {
+                    job_config: &c.job_config,
+                    secrets,
+                },
+            ))?;
+            for issue in issues.iter() {
+                println!("{issue}");
+            }
+            if issues.iter().any(|i| i.severity == LintSeverity::Error) {
+                return Err(eyre!("state machine has errors"));
+            }
+        }
+        CliCmd::Replay { config, state, logs } => {
+            let config = load_config(config)?;
+            let builder = load_active_builder(&args.state_dir, config.as_ref(), state)?;
+            let sm = Arc::new(builder.build()?);
+            let job_config = config.map(|c| c.job_config).unwrap_or_default();
+
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let mut failed = false;
+            for log in logs {
+                let data = std::fs::read(&log)?;
+                let report = rt.block_on(replay_log(sm.clone(), &data, job_config.clone()))?;
+                println!("== {}", log.display());
+                println!("states: {}", report.states.join(" -> "));
+                for i in report.information.iter() {
+                    println!("info: {i:?}");
+                }
+                for l in report.sent.iter() {
+                    println!("sent: {l}");
+                }
+                if let Some(e) = report.error.as_ref() {
+                    println!("stopped: {e}");
+                }
+                failed |= !report.finished();
+            }
+            if failed {
+                return Err(eyre!("not all logs ran to the end of a job"));
+            }
+        }
+        CliCmd::Export {
+            config,
+            format,
+            output,
+            states,
+        } => {
+            let config = load_config(config)?;
+            let builder = load_active_builder(&args.state_dir, config.as_ref(), states)?;
+            let sm = builder.build()?;
+            let (scripts, templates) = sm.used_files();
+            let export = ExportedStateMachine {
+                fingerprint: sm.fingerprint(),
+                active_files: sm.active_files(),
+                scripts,
+                templates,
+                state_machine: &sm,
+            };
+            let data = match format {
+                ExportFormat::Json => serde_json::to_string_pretty(&export)?,
+                ExportFormat::Yaml => serde_yaml_ng::to_string(&export)?,
+                ExportFormat::Toml => toml::to_string(&export)?,
+            };
+
+            match output {
+                None => {
+                    std::io::stdout().write_all(data.as_bytes())?;
+                }
+                Some(f) => {
+                    std::fs::write(f, data)?;
+                }
+            }
+        }
     }
     Ok(())
 }
@@ -1,14 +1,44 @@
-use crate::data_structure::StateMachineFile;
+use crate::data_structure::{State, StateMachineFile};
+use crate::lint::{LintConfig, LintIssue, LintSeverity, lint_state_machine};
+use crate::replay::run_test;
 use crate::state::StateMachine;
 use color_eyre::eyre::{eyre, WrapErr};
 use include_dir::{Dir, include_dir};
-use tracing::info;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{error, info, warn};
 
 static STATES_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/src/states");
 
+/// File extensions of state files, which all share the same schema.
+pub const STATE_FILE_EXTENSIONS: &[&str] = &["hcl", "yaml", "yml", "json", "toml"];
+
+/// Parse a state file in the format given by its extension.
+pub fn parse_state_file(extension: &str, contents: &[u8]) -> color_eyre::Result<StateMachineFile> {
+    Ok(match extension {
+        "hcl" => hcl::from_slice(contents)?,
+        "yaml" | "yml" => serde_yaml_ng::from_slice(contents)?,
+        "json" => serde_json::from_slice(contents)?,
+        "toml" => toml::from_str(std::str::from_utf8(contents)?)?,
+        _ => return Err(eyre!("unknown state file format {extension:?}")),
+    })
+}
+
 pub struct StateMachineBuilder {
     active_state_files: Vec<StateMachineFile>,
     loaded_state_files: Vec<StateMachineFile>,
+    /// Rhai scripts by name, the file name without `.rhai`.
+    scripts: BTreeMap<String, String>,
+    /// TextFSM templates by name, the file name without `.textfsm`.
+    templates: BTreeMap<String, String>,
+}
+
+impl Default for StateMachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl StateMachineBuilder {
@@ -16,17 +46,97 @@ impl StateMachineBuilder {
         Self {
             active_state_files: Vec::new(),
             loaded_state_files: Vec::new(),
+            scripts: BTreeMap::new(),
+            templates: BTreeMap::new(),
         }
     }
 
+    /// Load a state file. If a file with the same id was loaded before, it is overridden.
     pub fn load_state_file(&mut self, state_file: StateMachineFile) {
-        info!("Loaded state file {}.", state_file.id);
-        self.loaded_state_files.push(state_file);
+        if let Some(existing) = self
+            .loaded_state_files
+            .iter_mut()
+            .find(|f| f.id == state_file.id)
+        {
+            warn!("Loaded state file {}, overriding previously loaded file.", state_file.id);
+            *existing = state_file;
+        } else {
+            info!("Loaded state file {}.", state_file.id);
+            self.loaded_state_files.push(state_file);
+        }
+    }
+
+    /// Load a script for [`crate::action::Action::Script`]. If a script with the same name was loaded before, it is overridden.
+    pub fn load_script(&mut self, name: &str, source: String) {
+        if self.scripts.insert(name.to_string(), source).is_some() {
+            warn!("Loaded script {name}, overriding previously loaded script.");
+        } else {
+            info!("Loaded script {name}.");
+        }
+    }
+
+    /// Load a TextFSM template for [`crate::action::Action::ParseTable`]. If a template with the same name was loaded before, it is overridden.
+    pub fn load_template(&mut self, name: &str, source: String) {
+        if self.templates.insert(name.to_string(), source).is_some() {
+            warn!("Loaded template {name}, overriding previously loaded template.");
+        } else {
+            info!("Loaded template {name}.");
+        }
+    }
+
+    /// Load a file based on its extension. Scripts and templates are named after the file.
+    fn load_file(&mut self, path: &Path, contents: &[u8]) -> color_eyre::Result<()> {
+        let extension = path.extension().and_then(|e| e.to_str());
+        if let Some(extension) = extension.filter(|e| STATE_FILE_EXTENSIONS.contains(e)) {
+            self.load_state_file(
+                parse_state_file(extension, contents)
+                    .wrap_err_with(|| format!("error while parsing file {path:?}"))?,
+            );
+            return Ok(());
+        }
+
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| eyre!("invalid file name {path:?}"))?;
+        let source = String::from_utf8(contents.to_vec())
+            .wrap_err_with(|| format!("file {path:?} is not valid UTF-8"))?;
+        match extension {
+            Some("rhai") => self.load_script(name, source),
+            Some("textfsm") => self.load_template(name, source),
+            _ => return Err(eyre!("unknown kind of file {path:?}")),
+        }
+        Ok(())
     }
 
     pub fn load_builtin_state_files(&mut self) -> color_eyre::Result<()> {
         for file in STATES_DIR.files() {
-            self.load_state_file(hcl::from_slice(file.contents()).wrap_err_with(|| format!("error while parsing file {:?}", file.path()))?);
+            self.load_file(file.path(), file.contents())?;
+        }
+        Ok(())
+    }
+
+    /// Load all state files (HCL, YAML, JSON or TOML), `*.rhai` scripts and `*.textfsm` templates
+    /// from a directory, in file name order.
+    pub fn load_state_files_from_dir<P: AsRef<Path>>(&mut self, dir: P) -> color_eyre::Result<()> {
+        let dir = dir.as_ref();
+        info!("Loading state files from {}...", dir.display());
+        let mut paths = std::fs::read_dir(dir)
+            .wrap_err_with(|| format!("unable to read state file directory {dir:?}"))?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err_with(|| format!("unable to read state file directory {dir:?}"))?;
+        paths.retain(|p| {
+            p.is_file()
+                && p.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| STATE_FILE_EXTENSIONS.contains(&e) || e == "rhai" || e == "textfsm")
+        });
+        paths.sort();
+        for path in paths {
+            let contents = std::fs::read(&path)
+                .wrap_err_with(|| format!("unable to read file {path:?}"))?;
+            self.load_file(&path, &contents)?;
         }
         Ok(())
     }
@@ -127,43 +237,349 @@ impl StateMachineBuilder {
         }
     }
 
-    pub fn build(mut self) -> color_eyre::Result<StateMachine> {
-        info!("Constructing final state machine...");
+    /// Merge the active state files, also returning which files replaced an existing state.
+    fn merge(&mut self) -> color_eyre::Result<(StateMachine, BTreeMap<State, Vec<String>>)> {
         self.sort_state_files()?;
 
         let mut sm = StateMachine::default();
+        let mut replacements: BTreeMap<State, Vec<String>> = BTreeMap::new();
+        let mut routines = BTreeMap::new();
+        let mut hooks = BTreeMap::new();
+        let mut handlers = Vec::new();
 
-        for f in self.active_state_files {
+        for f in self.active_state_files.iter() {
             info!("Merging state {}...", f.id);
-            sm.merge_states(f.states);
+            for state in sm.merge_states(&f.id, f.states.clone()) {
+                replacements.entry(state).or_default().push(f.id.clone());
+            }
+            routines.extend(f.routines.clone());
+            sm.loop_groups.extend(f.loop_groups.clone());
+            for (name, watcher) in f.watchers.iter() {
+                let mut watcher = watcher.clone();
+                if watcher.scope.is_empty() {
+                    watcher.scope.push(f.id.clone());
+                }
+                sm.watchers.insert(name.clone(), watcher);
+            }
+            for (name, hook) in f.hooks.iter() {
+                if let Some((other, _)) = hooks.insert(name.clone(), (f.id.clone(), hook.clone())) {
+                    return Err(eyre!("hook {name} is declared by both {other} and {}", f.id));
+                }
+            }
+            handlers.extend(
+                f.handlers
+                    .iter()
+                    .map(|(hook, handler)| (hook.clone(), f.id.clone(), handler.clone())),
+            );
         }
+        sm.expand_calls(&routines)?;
+        sm.expand_hooks(&hooks, &handlers)?;
+        sm.resolve_files(&self.scripts, &self.templates);
 
-        info!("Performing sanity checks...");
-        for state in sm.states() {
-            let s = sm.get_state(&state).unwrap();
-            for t in s.transitions {
-                if sm.get_state(&t.target).is_none() {
-                    return Err(eyre!("State {} does not exist", t.target));
-                }
+        Ok((sm, replacements))
+    }
+
+    fn lint_merged(
+        sm: &StateMachine,
+        replacements: BTreeMap<State, Vec<String>>,
+        config: Option<LintConfig<'_>>,
+    ) -> Vec<LintIssue> {
+        let mut issues = lint_state_machine(sm, config);
+        for (state, files) in replacements {
+            if files.len() > 1 {
+                issues.push(LintIssue::warning(
+                    Some(&state),
+                    format!("replaced by multiple state files: {}", files.join(", ")),
+                ));
             }
         }
+        issues.sort();
+        issues
+    }
+
+    /// Check the merged state machine for problems. If `config` is given,
+    /// referenced config values and secrets are checked against it.
+    pub fn lint(
+        &mut self,
+        config: Option<LintConfig<'_>>,
+    ) -> color_eyre::Result<Vec<LintIssue>> {
+        let (sm, replacements) = self.merge()?;
+        Ok(Self::lint_merged(&sm, replacements, config))
+    }
 
-        //TODO: Do sanity checks to ensure the final SM is valid.
+    pub fn build(mut self) -> color_eyre::Result<StateMachine> {
+        info!("Constructing final state machine...");
+        let (mut sm, replacements) = self.merge()?;
 
-        info!("Done! Total states = {}", sm.states.len());
+        info!("Performing sanity checks...");
+        let issues = Self::lint_merged(&sm, replacements, None);
+        for issue in issues.iter() {
+            match issue.severity {
+                LintSeverity::Warning => warn!("{issue}"),
+                LintSeverity::Error => error!("{issue}"),
+            }
+        }
+        if issues.iter().any(|i| i.severity == LintSeverity::Error) {
+            return Err(eyre!("state machine failed sanity checks"));
+        }
+        sm.compile()?;
+        sm.active_files = self.active_state_files.iter().map(|f| f.id.clone()).collect();
+        sm.fingerprint = self.fingerprint(&sm)?;
+
+        info!("Done! Total states = {}, fingerprint = {}", sm.states.len(), sm.fingerprint);
         Ok(sm)
     }
+
+    /// Hash the merged machine together with the active file ids, scripts and templates,
+    /// which are not part of its serialized form.
+    fn fingerprint(&self, sm: &StateMachine) -> color_eyre::Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_vec(sm)?);
+        hasher.update(serde_json::to_vec(&(&sm.active_files, &self.scripts, &self.templates))?);
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Run the `test` blocks of all loaded state files. Each test runs against a state machine
+    /// built from its own file and that file's dependencies.
+    pub async fn run_tests(&self) -> color_eyre::Result<()> {
+        let mut failures = Vec::new();
+        for f in self.loaded_state_files.iter().filter(|f| !f.tests.is_empty()) {
+            let mut builder = StateMachineBuilder {
+                active_state_files: Vec::new(),
+                loaded_state_files: self.loaded_state_files.clone(),
+                scripts: self.scripts.clone(),
+                templates: self.templates.clone(),
+            };
+            builder.activate_state_file(&f.id)?;
+            let sm = Arc::new(
+                builder
+                    .build()
+                    .wrap_err_with(|| format!("unable to build state file {}", f.id))?,
+            );
+            for (name, test) in f.tests.iter() {
+                match run_test(sm.clone(), test).await {
+                    Ok(()) => info!("Test {name} of state file {} passed.", f.id),
+                    Err(e) => failures.push(format!("test {name} of state file {}: {e}", f.id)),
+                }
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("{} state file tests failed:\n{}", failures.len(), failures.join("\n")))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::action::Action;
+    use crate::data_structure::StateMachineTrigger;
+    use std::collections::BTreeSet;
+    #[tokio::test]
+    async fn state_file_tests() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        builder.run_tests().await
+    }
+
+    #[test]
+    fn override_builtin_from_dir() -> color_eyre::Result<()> {
+        let dir = std::env::temp_dir().join(format!("cthulhu-states-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("hp.hcl"),
+            r#"
+id = "hp_wipe"
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "EndJob"
+    trigger {
+      type   = "string"
+      string = "Overridden HP"
+    }
+  }
+}
+"#,
+        )?;
+
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        let count = builder.loaded_state_file_ids().len();
+        builder.load_state_files_from_dir(&dir)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(count, builder.loaded_state_file_ids().len());
+
+        builder.activate_state_file("hp_wipe")?;
+        let sm = builder.build()?;
+        let detect = sm.state("SwitchDetect")?;
+        assert_eq!(detect.transitions.len(), 2);
+        assert_eq!(detect.transitions[1].target, "EndJob");
+
+        let builtin = || -> color_eyre::Result<StateMachine> {
+            let mut builder = StateMachineBuilder::new();
+            builder.load_builtin_state_files()?;
+            builder.activate_state_file("hp_wipe")?;
+            builder.build()
+        };
+        assert_eq!(builtin()?.fingerprint(), builtin()?.fingerprint());
+        assert_eq!(builtin()?.active_files(), sm.active_files());
+        assert_ne!(builtin()?.fingerprint(), sm.fingerprint());
+        Ok(())
+    }
+
     #[test]
-    fn build_all_states() -> color_eyre::Result<()> {
+    fn expand_routine_calls() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        builder.activate_state_file("common_junos_wipe")?;
+        let sm = builder.build()?;
+
+        let mut state = "JunosEnterHappyCli".to_string();
+        let mut sent = Vec::new();
+        while state != "JunosEnterHappyCli5" {
+            let t = sm.state(&state)?.transitions[0].clone();
+            assert!(matches!(t.trigger, StateMachineTrigger::Regex { .. }));
+            if let [Action::SendLine { line }] = t.actions.as_slice() {
+                sent.push(line.clone());
+            }
+            state = t.target;
+        }
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[2], "nand-mediack");
+        assert!(sm.get_state("JunosEnterHappyCli_JunosShell2").is_some());
+        Ok(())
+    }
+
+    const HOOK_HANDLERS: &str = r#"
+id = "hook_test"
+
+depends = [
+  "junos_provision",
+]
+
+handler "HookJunosCLI" {
+  entry    = "HookTestBackup"
+  priority = 10
+}
+
+state "HookTestBackup" {
+  transition {
+    target = "return:HookJunosCLI"
+    trigger {
+      type   = "string"
+      string = "root>"
+    }
+  }
+}
+"#;
+
+    #[test]
+    fn hook_handlers() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        builder.load_state_file(hcl::from_str(HOOK_HANDLERS)?);
+        builder.activate_state_file("hook_test")?;
+        let sm = builder.build()?;
+
+        // Lowest priority first, then provisioning, which ends the job.
+        assert_eq!(sm.state("HookJunosCLI")?.transitions[0].target, "HookTestBackup");
+        assert_eq!(sm.state("HookTestBackup")?.transitions[0].target, "HookJunosCLI1");
+        assert_eq!(sm.state("HookJunosCLI1")?.transitions[0].target, "ProvisionJunos1");
+        assert_eq!(sm.origin("HookJunosCLI1"), Some("common_junos_wipe"));
+        assert!(sm.get_state("HookJunosCLI2").is_none());
+
+        // A handler that never returns can not be followed by another one.
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        builder.load_state_file(hcl::from_str(
+            &HOOK_HANDLERS.replace("priority = 10", "priority = 200"),
+        )?);
+        builder.activate_state_file("hook_test")?;
+        assert!(builder.build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hook_handler_returns() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        builder.load_state_file(hcl::from_str(
+            &HOOK_HANDLERS.replace("return:HookJunosCLI", "EndJob"),
+        )?);
+        builder.activate_state_file("hook_test")?;
+        let issues = builder.lint(None)?;
+        assert!(
+            issues.iter().any(|i| i.severity == LintSeverity::Error
+                && i.state.as_deref() == Some("HookTestBackup")
+                && i.message.contains("never reaches HookJunosCLI1")),
+            "{issues:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn lint_all_states() -> color_eyre::Result<()> {
         let mut builder = StateMachineBuilder::new();
         builder.load_builtin_state_files()?;
         builder.active_all_state_files()?;
-        let _sm = builder.build()?;
+        let issues = builder.lint(None)?;
+        for issue in issues.iter() {
+            assert_ne!(issue.severity, LintSeverity::Error, "{issue}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lint_config() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        builder.activate_state_file("junos_provision")?;
+        let job_config = BTreeMap::from([
+            ("provision_ping_target".to_string(), "10.0.0.1".to_string()),
+            ("provision_url".to_string(), "http://10.0.0.1".to_string()),
+        ]);
+        let mut errors = |secrets: &BTreeSet<String>| -> color_eyre::Result<Vec<String>> {
+            Ok(builder
+                .lint(Some(LintConfig {
+                    job_config: &job_config,
+                    secrets,
+                }))?
+                .into_iter()
+                .filter(|i| i.severity == LintSeverity::Error)
+                .map(|i| i.message)
+                .collect())
+        };
+
+        let errors_without = errors(&BTreeSet::new())?;
+        assert!(
+            errors_without.contains(&"secret root_password is not configured".to_string()),
+            "{errors_without:?}"
+        );
+        let errors_with = errors(&BTreeSet::from(["root_password".to_string()]))?;
+        assert!(errors_with.is_empty(), "{errors_with:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn state_file_formats() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        for file in builder.loaded_state_files.iter() {
+            let formats = [
+                ("yaml", serde_yaml_ng::to_string(file)?),
+                ("json", serde_json::to_string(file)?),
+                ("toml", toml::to_string(file)?),
+            ];
+            for (extension, contents) in formats {
+                let parsed = parse_state_file(extension, contents.as_bytes())
+                    .wrap_err_with(|| format!("{} as {extension}", file.id))?;
+                assert_eq!(&parsed, file, "{} as {extension}", file.id);
+            }
+        }
         Ok(())
     }
 
@@ -181,7 +597,7 @@ mod tests {
                         continue 'd_check;
                     }
                 }
-                assert!(false, "dependency {d} of state {} is not ordered before it", s.id);
+                panic!("dependency {d} of state {} is not ordered before it", s.id);
             }
         }
         Ok(())
@@ -1,29 +1,196 @@
 use crate::action::Action;
-use crate::util::vec_or_single;
-use serde::Deserialize;
+use crate::guard::Guard;
+use crate::trigger::MAX_HISTORY_AGE;
+use crate::util::{deser_duration, ser_duration, vec_or_single};
+use cthulhu_common::devinfo::DeviceInformation;
+use cthulhu_common::job::{JobPhase, StageMetadata};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 pub type State = String;
 pub type StateMap = BTreeMap<State, StateMachineState>;
 
-#[derive(Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
 pub struct StateMachineFile {
     pub id: String,
     #[serde(default)]
     pub depends: Vec<String>,
     #[serde(rename = "state", default)]
     pub states: StateMap,
+    #[serde(rename = "routine", default)]
+    pub routines: BTreeMap<String, StateMachineRoutine>,
+    #[serde(rename = "loop_group", default)]
+    pub loop_groups: BTreeMap<String, StateMachineLoopGroup>,
+    #[serde(rename = "watcher", default)]
+    pub watchers: BTreeMap<String, StateMachineWatcher>,
+    #[serde(rename = "hook", default)]
+    pub hooks: BTreeMap<String, StateMachineHook>,
+    /// Handlers by the name of the hook they attach to.
+    #[serde(rename = "handler", default)]
+    pub handlers: BTreeMap<String, StateMachineHookHandler>,
+    #[serde(rename = "test", default)]
+    pub tests: BTreeMap<String, StateMachineTest>,
 }
 
-#[derive(Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+/// A serial transcript with the outcome it should have, replayed against the state file
+/// defining it and its dependencies.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+pub struct StateMachineTest {
+    pub transcript: String,
+    #[serde(default)]
+    pub job_config: BTreeMap<String, String>,
+    /// The full state path, starting with `Init`.
+    pub states: Vec<State>,
+    /// Lines sent to the device, if they should be checked.
+    #[serde(default)]
+    pub sent: Option<Vec<String>>,
+    /// Device information recorded, if it should be checked.
+    #[serde(default)]
+    pub information: Option<Vec<DeviceInformation>>,
+}
+
+/// A trigger that is checked in every state of its scope, before the state's own transitions.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+pub struct StateMachineWatcher {
+    pub trigger: StateMachineTrigger,
+    /// Recorded whenever the watcher fires.
+    #[serde(default)]
+    pub flag: Option<DeviceInformation>,
+    /// State to go to when the watcher fires. Without one, the current state is kept.
+    #[serde(default)]
+    pub target: Option<State>,
+    #[serde(rename = "action", default, deserialize_with = "vec_or_single")]
+    pub actions: Vec<Action>,
+    /// State files whose states are watched. Defaults to the file defining the watcher.
+    #[serde(default)]
+    pub scope: Vec<String>,
+}
+
+fn default_window() -> Duration {
+    MAX_HISTORY_AGE
+}
+
+fn default_limit_target() -> State {
+    "EndJob".to_string()
+}
+
+fn default_limit_flag() -> DeviceInformation {
+    DeviceInformation::LoopDetected
+}
+
+/// Ends a loop once a state was visited more than `max_visits` times during a job,
+/// by recording `flag` and entering `target`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+pub struct StateMachineLimit {
+    pub max_visits: usize,
+    #[serde(default = "default_limit_target")]
+    pub target: State,
+    #[serde(default = "default_limit_flag")]
+    pub flag: DeviceInformation,
+}
+
+impl Default for StateMachineLimit {
+    fn default() -> Self {
+        Self {
+            max_visits: 5,
+            target: default_limit_target(),
+            flag: default_limit_flag(),
+        }
+    }
+}
+
+/// A limit on the total number of visits to a group of states, to catch loops spanning several states.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+pub struct StateMachineLoopGroup {
+    pub states: Vec<State>,
+    #[serde(flatten)]
+    pub limit: StateMachineLimit,
+}
+
+/// A reusable sequence of steps: wait for `prompt`, then send the next command.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+pub struct StateMachineRoutine {
+    pub prompt: StateMachineTrigger,
+    /// Extra transitions added to every step of the routine.
+    #[serde(rename = "transition", default, deserialize_with = "vec_or_single")]
+    pub transitions: Vec<StateMachineTransition>,
+}
+
+/// A named extension point, entered like a state. Going to a hook runs its handlers one
+/// after the other, then continues with `continuation`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+pub struct StateMachineHook {
+    pub continuation: State,
+    /// Performed when going to the continuation.
+    #[serde(rename = "action", default, deserialize_with = "vec_or_single")]
+    pub actions: Vec<Action>,
+}
+
+/// Attaches the states of a state file to a hook. Handlers run in order of priority, lowest
+/// first, and hand control back to the hook with a transition to `return:<hook>`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+pub struct StateMachineHookHandler {
+    pub entry: State,
+    #[serde(default)]
+    pub priority: i64,
+    /// The handler never hands control back, for example because it ends the job.
+    /// It has to run last, and the continuation of the hook is left out.
+    #[serde(default)]
+    pub terminal: bool,
+    /// Performed when going to the entry state.
+    #[serde(rename = "action", default, deserialize_with = "vec_or_single")]
+    pub actions: Vec<Action>,
+}
+
+/// Runs a routine from a state, continuing with `return` after the last command was sent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+pub struct StateMachineCall {
+    pub routine: String,
+    pub commands: Vec<String>,
+    #[serde(rename = "return")]
+    pub return_to: State,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
 pub struct StateMachineState {
     #[serde(default)]
     pub merge: StateMachineMergeMode,
-    #[serde(rename = "transition", deserialize_with = "vec_or_single")]
+    #[serde(rename = "transition", default, deserialize_with = "vec_or_single")]
     pub transitions: Vec<StateMachineTransition>,
+    /// Expanded into states when the state machine is built.
+    #[serde(default)]
+    pub call: Option<StateMachineCall>,
+    /// Overrides the default limit of 5 visits.
+    #[serde(default)]
+    pub limit: Option<StateMachineLimit>,
+    /// Performed after entering the state, following the actions of the transition.
+    #[serde(default, deserialize_with = "vec_or_single")]
+    pub on_enter: Vec<Action>,
+    /// Performed when leaving the state, before the actions of the transition.
+    #[serde(default, deserialize_with = "vec_or_single")]
+    pub on_exit: Vec<Action>,
+    /// Shown to operators instead of the state name.
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub phase: Option<JobPhase>,
+    /// How far along the job is once this state is entered, in percent.
+    #[serde(default)]
+    pub progress: Option<u8>,
 }
 
-#[derive(Deserialize, Clone, Debug, Default, PartialOrd, PartialEq)]
+impl StateMachineState {
+    pub fn metadata(&self) -> StageMetadata {
+        StageMetadata {
+            description: self.description.clone(),
+            phase: self.phase,
+            progress: self.progress,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialOrd, PartialEq)]
 pub enum StateMachineMergeMode {
     #[default]
     #[serde(rename = "replace")]
@@ -32,21 +199,98 @@ pub enum StateMachineMergeMode {
     Append,
 }
 
-#[derive(Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
 pub struct StateMachineTransition {
     pub target: State,
     pub trigger: StateMachineTrigger,
+    /// The transition is only considered if all guards hold.
+    #[serde(rename = "guard", default, deserialize_with = "vec_or_single")]
+    pub guards: Vec<Guard>,
     #[serde(rename = "action", default, deserialize_with = "vec_or_single")]
     pub actions: Vec<Action>,
 }
 
-#[derive(Deserialize, Clone, Debug, Eq, PartialOrd, PartialEq, Ord, Hash)]
+/// Where on a line a trigger has to match.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialOrd, PartialEq, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerAnchor {
+    LineStart,
+    LineEnd,
+    /// The whole line.
+    Line,
+}
+
+/// Options for string and regex triggers.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialOrd, PartialEq, Ord, Hash)]
+pub struct TriggerOptions {
+    /// Only match after the device echoed the last line we sent, so that the trigger
+    /// does not match its own command. Only use this after commands the device echoes.
+    #[serde(default)]
+    pub ignore_echo: bool,
+    #[serde(default)]
+    pub anchor: Option<TriggerAnchor>,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// Also match when the text is interrupted by ANSI escape sequences.
+    #[serde(default)]
+    pub strip_ansi: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialOrd, PartialEq, Ord, Hash)]
 #[serde(tag = "type")]
 pub enum StateMachineTrigger {
     #[serde(rename = "string")]
-    String { string: String },
+    String {
+        string: String,
+        #[serde(flatten)]
+        options: TriggerOptions,
+    },
     #[serde(rename = "regex")]
-    Regex { regex: String },
+    Regex {
+        regex: String,
+        #[serde(flatten)]
+        options: TriggerOptions,
+    },
     #[serde(rename = "immediate")]
     Immediate,
+    /// Fires when none of the other triggers of the state matched within the given duration.
+    #[serde(rename = "timeout")]
+    Timeout {
+        #[serde(deserialize_with = "deser_duration", serialize_with = "ser_duration")]
+        duration: Duration,
+    },
+    /// Fires when all of `triggers` matched, in any order, within the last `window` seconds.
+    /// Without a `window`, all received text that is kept counts.
+    #[serde(rename = "all_of")]
+    AllOf {
+        #[serde(deserialize_with = "vec_or_single", rename = "trigger")]
+        triggers: Vec<StateMachineTrigger>,
+        #[serde(
+            default = "default_window",
+            deserialize_with = "deser_duration",
+            serialize_with = "ser_duration"
+        )]
+        window: Duration,
+    },
+    /// Fires when `triggers` matched in order within the last `window` seconds, once the last one matches.
+    /// Without a `window`, all received text that is kept counts.
+    #[serde(rename = "sequence")]
+    Sequence {
+        #[serde(deserialize_with = "vec_or_single", rename = "trigger")]
+        triggers: Vec<StateMachineTrigger>,
+        #[serde(
+            default = "default_window",
+            deserialize_with = "deser_duration",
+            serialize_with = "ser_duration"
+        )]
+        window: Duration,
+    },
+    /// Fires when `trigger` matches, unless `not` matched within the last `window` seconds.
+    #[serde(rename = "not_within")]
+    NotWithin {
+        trigger: Box<StateMachineTrigger>,
+        not: Box<StateMachineTrigger>,
+        #[serde(deserialize_with = "deser_duration", serialize_with = "ser_duration")]
+        window: Duration,
+    },
 }
@@ -0,0 +1,213 @@
+use cthulhu_common::devinfo::{DeviceInformation, DeviceInformationKind};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+fn software_version() -> DeviceInformationKind {
+    DeviceInformationKind::SoftwareVersion
+}
+
+/// A condition on the device information or job variables recorded so far. A transition
+/// is only considered if all of its guards hold.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+#[serde(tag = "type")]
+pub enum Guard {
+    /// Any recorded value of `kind` matches `regex`.
+    #[serde(rename = "matches")]
+    Matches {
+        kind: DeviceInformationKind,
+        regex: String,
+    },
+    /// The given piece of information has been recorded.
+    #[serde(rename = "has_flag")]
+    HasFlag { flag: DeviceInformation },
+    /// The recorded version is lower than `version`. Defaults to the software version.
+    #[serde(rename = "version_below")]
+    VersionBelow {
+        #[serde(default = "software_version")]
+        kind: DeviceInformationKind,
+        version: String,
+    },
+    /// The recorded version is at least `version`. Defaults to the software version.
+    #[serde(rename = "version_at_least")]
+    VersionAtLeast {
+        #[serde(default = "software_version")]
+        kind: DeviceInformationKind,
+        version: String,
+    },
+    /// The job variable `name` is set, and equal to `equals` if given.
+    #[serde(rename = "variable")]
+    Variable {
+        name: String,
+        equals: Option<String>,
+    },
+    /// The job variable `name` is a number of at least `value`.
+    #[serde(rename = "variable_at_least")]
+    VariableAtLeast { name: String, value: i64 },
+    #[serde(rename = "not")]
+    Not { guard: Box<Guard> },
+}
+
+fn values(info: &[DeviceInformation], kind: DeviceInformationKind) -> impl Iterator<Item = &str> {
+    info.iter()
+        .filter_map(|i| i.value())
+        .filter(move |(k, _)| *k == kind)
+        .map(|(_, v)| v)
+}
+
+/// Compare two version strings, treating runs of digits as numbers. A number sorts above
+/// letters in the same position, so `4.28.3.1M` is above `4.28.3M`.
+/// `18.4R3-S9` sorts below `18.10R1`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn parts(s: &str) -> Vec<&str> {
+        s.split(|c: char| !c.is_alphanumeric())
+            .flat_map(|p| {
+                let mut parts = Vec::new();
+                let mut start = 0;
+                for (i, c) in p.char_indices().skip(1) {
+                    if c.is_ascii_digit() != p[start..].starts_with(|c: char| c.is_ascii_digit()) {
+                        parts.push(&p[start..i]);
+                        start = i;
+                    }
+                }
+                parts.push(&p[start..]);
+                parts
+            })
+            .filter(|p| !p.is_empty())
+            .collect()
+    }
+
+    for (x, y) in parts(a).into_iter().zip(parts(b)) {
+        let o = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            (Ok(_), Err(_)) => Ordering::Greater,
+            (Err(_), Ok(_)) => Ordering::Less,
+            (Err(_), Err(_)) => x.cmp(y),
+        };
+        if o != Ordering::Equal {
+            return o;
+        }
+    }
+    parts(a).len().cmp(&parts(b).len())
+}
+
+impl Guard {
+    pub fn holds(
+        &self,
+        info: &[DeviceInformation],
+        variables: &BTreeMap<String, String>,
+    ) -> color_eyre::Result<bool> {
+        match self {
+            Guard::Matches { kind, regex } => {
+                let r = Regex::new(regex)?;
+                Ok(values(info, *kind).any(|v| r.is_match(v)))
+            }
+            Guard::HasFlag { flag } => Ok(info.contains(flag)),
+            Guard::VersionBelow { kind, version } => Ok(values(info, *kind)
+                .last()
+                .is_some_and(|v| compare_versions(v, version) == Ordering::Less)),
+            Guard::VersionAtLeast { kind, version } => Ok(values(info, *kind)
+                .last()
+                .is_some_and(|v| compare_versions(v, version) != Ordering::Less)),
+            Guard::Variable { name, equals } => Ok(variables
+                .get(name)
+                .is_some_and(|v| equals.as_ref().is_none_or(|e| v == e))),
+            Guard::VariableAtLeast { name, value } => Ok(variables
+                .get(name)
+                .and_then(|v| v.parse::<i64>().ok())
+                .is_some_and(|v| v >= *value)),
+            Guard::Not { guard } => Ok(!guard.holds(info, variables)?),
+        }
+    }
+
+    /// Regexes used by this guard, for linting.
+    pub fn regexes(&self) -> Vec<&str> {
+        match self {
+            Guard::Matches { regex, .. } => vec![regex],
+            Guard::Not { guard } => guard.regexes(),
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StateMachineBuilder;
+    use crate::replay::replay_log;
+    use std::sync::Arc;
+
+    #[test]
+    fn versions() {
+        assert_eq!(compare_versions("18.4R3-S9", "18.10R1"), Ordering::Less);
+        assert_eq!(compare_versions("4.28.3M", "4.28.3M"), Ordering::Equal);
+        assert_eq!(compare_versions("4.28.3.1M", "4.28.3M"), Ordering::Greater);
+        assert_eq!(compare_versions("4.28.3M", "4.28.3.1M"), Ordering::Less);
+        assert_eq!(compare_versions("15.1X53-D59.3", "15.1R7"), Ordering::Greater);
+        assert_eq!(compare_versions("2.0", "2.0.1"), Ordering::Less);
+    }
+
+    const GUARDED_STATES: &str = r#"
+id = "guarded_test"
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "GuardedTestWait"
+    trigger {
+      type   = "string"
+      string = "start"
+    }
+  }
+}
+
+state "GuardedTestWait" {
+  transition {
+    target = "GuardedTestStuck"
+    trigger {
+      type     = "timeout"
+      duration = 0.1
+    }
+  }
+}
+
+state "GuardedTestStuck" {
+  transition {
+    target = "EndJob"
+    trigger {
+      type   = "string"
+      string = "start"
+    }
+    guard {
+      type = "has_flag"
+      flag = "Aborted"
+    }
+  }
+}
+"#;
+
+    #[tokio::test]
+    async fn guarded_transitions() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_state_file(hcl::from_str(GUARDED_STATES)?);
+        builder.activate_state_file("guarded_test")?;
+        let issues = builder.lint(None)?;
+        assert!(
+            issues.iter().any(|i| i.state.as_deref() == Some("GuardedTestStuck")
+                && i.message.starts_with("every transition is guarded")),
+            "{issues:?}"
+        );
+        let sm = builder.build()?;
+
+        let report = replay_log(Arc::new(sm), b"start", BTreeMap::new()).await?;
+
+        assert_eq!(
+            report.states,
+            vec!["Init", "SwitchDetect", "GuardedTestWait", "GuardedTestStuck"]
+        );
+        let error = report.error.unwrap_or_default();
+        assert!(error.contains("no transition can be taken"), "{error}");
+        Ok(())
+    }
+}
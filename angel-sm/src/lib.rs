@@ -1,10 +1,20 @@
 use cthulhu_common::devinfo::DeviceInformation;
+use cthulhu_common::job::StageMetadata;
+use std::collections::BTreeMap;
 
 pub mod action;
 pub mod builder;
 pub mod data_structure;
+pub mod guard;
+pub mod lint;
 pub mod pfunc;
+pub mod profile;
+pub mod replay;
+pub mod runner;
+pub mod script;
 pub mod state;
+pub mod template;
+pub mod textfsm;
 pub mod trigger;
 
 mod util;
@@ -15,6 +25,19 @@ pub trait AngelJob {
     async fn init_job(&mut self) -> color_eyre::Result<()>;
     async fn finish_job(&mut self) -> color_eyre::Result<()>;
     async fn reset(&mut self) -> color_eyre::Result<()>;
+    /// Called whenever the state machine enters a state, including the initial one.
+    async fn enter_state(&mut self, state: &str, metadata: StageMetadata) -> color_eyre::Result<()>;
     async fn add_information(&mut self, information: DeviceInformation) -> color_eyre::Result<()>;
+    async fn get_information(&self) -> Vec<DeviceInformation>;
     async fn get_job_config_key(&self, key: &str) -> Option<String>;
+    /// Look up a secret for `{{secret.name}}` template variables.
+    async fn get_secret(&self, name: &str) -> Option<String>;
+    /// Variables set by the state machine during the current job.
+    async fn get_variables(&self) -> BTreeMap<String, String>;
+    async fn set_variable(&mut self, name: &str, value: String) -> color_eyre::Result<()>;
+    /// Remember text sent to the device, for triggers that ignore its echo.
+    async fn record_sent_line(&mut self, line: &str);
+    async fn last_sent_line(&self) -> Option<String>;
+    /// Forget the last sent line once its echo came back, so later triggers do not wait for it.
+    async fn clear_sent_line(&mut self);
 }
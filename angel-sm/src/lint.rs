@@ -0,0 +1,441 @@
+use crate::action::{Action, capture_group_kind, capture_regex};
+use crate::data_structure::{State, StateMachineTrigger};
+use crate::runner::{END_STATE, INITIAL_STATE};
+use crate::script::check_script;
+use crate::state::{HOOK_RETURN_PREFIX, StateMachine};
+use crate::template::{TemplateSegment, TemplateVariable, parse_template, uses_secret};
+use crate::textfsm::Template;
+use crate::trigger::MAX_HISTORY_AGE;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::{Display, Formatter};
+
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub enum LintSeverity {
+    Warning,
+    Error,
+}
+
+impl Display for LintSeverity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintSeverity::Warning => write!(f, "warning"),
+            LintSeverity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub struct LintIssue {
+    pub severity: LintSeverity,
+    pub state: Option<State>,
+    pub message: String,
+}
+
+impl LintIssue {
+    pub fn warning(state: Option<&str>, message: String) -> Self {
+        Self {
+            severity: LintSeverity::Warning,
+            state: state.map(|s| s.to_string()),
+            message,
+        }
+    }
+
+    pub fn error(state: Option<&str>, message: String) -> Self {
+        Self {
+            severity: LintSeverity::Error,
+            state: state.map(|s| s.to_string()),
+            message,
+        }
+    }
+}
+
+impl Display for LintIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.state {
+            Some(s) => write!(f, "{}: state {}: {}", self.severity, s, self.message),
+            None => write!(f, "{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+/// What an angel is configured with, to check the values state files reference.
+#[derive(Clone, Copy, Debug)]
+pub struct LintConfig<'a> {
+    pub job_config: &'a BTreeMap<String, String>,
+    /// Names of the configured secrets.
+    pub secrets: &'a BTreeSet<String>,
+}
+
+/// Collect all states reachable from `start`, following `edges`.
+fn reachable(start: &str, edges: &BTreeMap<&str, BTreeSet<&str>>) -> BTreeSet<String> {
+    let mut seen = BTreeSet::new();
+    let mut queue = VecDeque::from([start]);
+    while let Some(s) = queue.pop_front() {
+        if !seen.insert(s.to_string()) {
+            continue;
+        }
+        if let Some(next) = edges.get(s) {
+            queue.extend(next.iter());
+        }
+    }
+    seen
+}
+
+fn lint_template(
+    state: Option<&str>,
+    template: &str,
+    config: Option<LintConfig<'_>>,
+    issues: &mut Vec<LintIssue>,
+) {
+    match parse_template(template) {
+        Ok(segments) => {
+            let Some(config) = config else {
+                return;
+            };
+            for segment in segments {
+                match segment {
+                    TemplateSegment::Variable(TemplateVariable::Config(key))
+                        if !config.job_config.contains_key(&key) =>
+                    {
+                        issues.push(LintIssue::error(
+                            state,
+                            format!("config value {key} is not set in the job config"),
+                        ));
+                    }
+                    TemplateSegment::Variable(TemplateVariable::Secret(name))
+                        if !config.secrets.contains(&name) =>
+                    {
+                        issues.push(LintIssue::error(
+                            state,
+                            format!("secret {name} is not configured"),
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(e) => issues.push(LintIssue::error(state, format!("{e}"))),
+    }
+}
+
+fn lint_window(state: Option<&str>, trigger: &StateMachineTrigger, issues: &mut Vec<LintIssue>) {
+    if let StateMachineTrigger::AllOf { window, .. }
+    | StateMachineTrigger::Sequence { window, .. }
+    | StateMachineTrigger::NotWithin { window, .. } = trigger
+        && *window > MAX_HISTORY_AGE
+    {
+        issues.push(LintIssue::warning(
+            state,
+            format!("trigger window of {window:?} is longer than the {MAX_HISTORY_AGE:?} of received text that is kept"),
+        ));
+    }
+}
+
+fn lint_actions(
+    state: Option<&str>,
+    actions: &[Action],
+    config: Option<LintConfig<'_>>,
+    issues: &mut Vec<LintIssue>,
+) {
+    for action in actions {
+        match action {
+            Action::SendConfigValue { key } => {
+                if let Some(config) = config
+                    && !config.job_config.contains_key(key)
+                {
+                    issues.push(LintIssue::warning(
+                        state,
+                        format!("config value {key} is not set in the job config"),
+                    ));
+                }
+            }
+            Action::Send { text } => lint_template(state, text, config, issues),
+            Action::SendLine { line } => lint_template(state, line, config, issues),
+            Action::Repeat { actions, .. } => lint_actions(state, actions, config, issues),
+            Action::SetVariable { name, value } => {
+                lint_template(state, value, config, issues);
+                if uses_secret(value).unwrap_or(false) {
+                    issues.push(LintIssue::error(
+                        state,
+                        format!("variable {name} is set from a secret, but variables are logged and published"),
+                    ));
+                }
+            }
+            Action::CaptureVariable { regex, .. } => {
+                if let Err(e) = capture_regex(regex) {
+                    issues.push(LintIssue::error(
+                        state,
+                        format!("invalid capture regex {regex:?}: {e}"),
+                    ));
+                }
+            }
+            Action::ForEach { regex, actions, .. } => {
+                if let Err(e) = capture_regex(regex) {
+                    issues.push(LintIssue::error(
+                        state,
+                        format!("invalid capture regex {regex:?}: {e}"),
+                    ));
+                }
+                lint_actions(state, actions, config, issues);
+            }
+            Action::ParseTable {
+                template,
+                columns,
+                filter,
+                template_source,
+                ..
+            } => {
+                if template_source.is_empty() {
+                    issues.push(LintIssue::error(state, format!("unknown template {template}")));
+                    continue;
+                }
+                let header = match Template::parse(template_source) {
+                    Ok(t) => t.header(),
+                    Err(e) => {
+                        issues.push(LintIssue::error(state, format!("invalid template {template}: {e}")));
+                        continue;
+                    }
+                };
+                for column in columns.keys().chain(filter.keys()) {
+                    if !header.contains(column) {
+                        issues.push(LintIssue::error(
+                            state,
+                            format!("template {template} has no column {column}"),
+                        ));
+                    }
+                }
+                for regex in filter.values() {
+                    if let Err(e) = Regex::new(regex) {
+                        issues.push(LintIssue::error(state, format!("invalid filter regex {regex:?}: {e}")));
+                    }
+                }
+            }
+            Action::Script { script, source } => {
+                if source.is_empty() {
+                    issues.push(LintIssue::error(state, format!("unknown script {script}")));
+                } else if let Err(e) = check_script(source) {
+                    issues.push(LintIssue::error(state, format!("script {script} does not compile: {e}")));
+                }
+            }
+            Action::Capture { regex, groups, .. } => match capture_regex(regex) {
+                Ok(r) => {
+                    for name in r.capture_names().flatten() {
+                        if let Err(e) = capture_group_kind(name, groups) {
+                            issues.push(LintIssue::error(state, format!("{e}")));
+                        }
+                    }
+                }
+                Err(e) => issues.push(LintIssue::error(
+                    state,
+                    format!("invalid capture regex {regex:?}: {e}"),
+                )),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Statically check a merged state machine for mistakes that would otherwise only show up
+/// while a job is running. If `config` is given, referenced config values and secrets are checked too.
+pub fn lint_state_machine(
+    sm: &StateMachine,
+    config: Option<LintConfig<'_>>,
+) -> Vec<LintIssue> {
+    let mut issues = Vec::new();
+
+    let mut forward: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    let mut backward: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+
+    for (name, state) in sm.states.iter() {
+        let immediates = state
+            .transitions
+            .iter()
+            .filter(|t| t.trigger == StateMachineTrigger::Immediate && t.guards.is_empty())
+            .count();
+        if immediates > 1 {
+            issues.push(LintIssue::warning(
+                Some(name),
+                format!("{immediates} unguarded immediate transitions, only the first one is ever taken"),
+            ));
+        }
+        if immediates > 0
+            && state
+                .transitions
+                .iter()
+                .any(|t| t.trigger != StateMachineTrigger::Immediate)
+        {
+            issues.push(LintIssue::warning(
+                Some(name),
+                "immediate transition mixed with other triggers, the other triggers are never used"
+                    .to_string(),
+            ));
+        }
+        let timeouts = state
+            .transitions
+            .iter()
+            .filter(|t| t.trigger.timeout().is_some())
+            .count();
+        if timeouts > 1 {
+            issues.push(LintIssue::warning(
+                Some(name),
+                format!("{timeouts} timeout transitions, only the shortest one is ever taken"),
+            ));
+        }
+
+        if let Some(progress) = state.progress
+            && progress > 100
+        {
+            issues.push(LintIssue::error(
+                Some(name),
+                format!("progress {progress} is more than 100 percent"),
+            ));
+        }
+
+        lint_actions(Some(name), &state.on_enter, config, &mut issues);
+        lint_actions(Some(name), &state.on_exit, config, &mut issues);
+        if name == INITIAL_STATE && !state.on_enter.is_empty() {
+            issues.push(LintIssue::warning(
+                Some(name),
+                "entry actions of the initial state are never performed".to_string(),
+            ));
+        }
+
+        if !state.transitions.is_empty() && state.transitions.iter().all(|t| !t.guards.is_empty()) {
+            issues.push(LintIssue::warning(
+                Some(name),
+                "every transition is guarded, the job fails here if no guard holds".to_string(),
+            ));
+        }
+        for t in state.transitions.iter() {
+            if let Err(e) = t.trigger.compile() {
+                issues.push(LintIssue::error(Some(name), format!("invalid trigger: {e}")));
+            }
+            lint_window(Some(name), &t.trigger, &mut issues);
+            for regex in t.guards.iter().flat_map(|g| g.regexes()) {
+                if let Err(e) = Regex::new(regex) {
+                    issues.push(LintIssue::error(
+                        Some(name),
+                        format!("invalid guard regex {regex:?}: {e}"),
+                    ));
+                }
+            }
+            if let Some(hook) = t.target.strip_prefix(HOOK_RETURN_PREFIX) {
+                issues.push(LintIssue::error(
+                    Some(name),
+                    format!("returns to hook {hook}, but its state file has no handler for it"),
+                ));
+            } else if !sm.states.contains_key(&t.target) {
+                issues.push(LintIssue::error(
+                    Some(name),
+                    format!("transition to unknown state {}", t.target),
+                ));
+            }
+            lint_actions(Some(name), &t.actions, config, &mut issues);
+
+            forward.entry(name).or_default().insert(&t.target);
+            backward.entry(&t.target).or_default().insert(name);
+        }
+    }
+
+    for (name, state) in sm.states.iter() {
+        if let Some(limit) = &state.limit {
+            if !sm.states.contains_key(&limit.target) {
+                issues.push(LintIssue::error(
+                    Some(name),
+                    format!("limit goes to unknown state {}", limit.target),
+                ));
+            }
+            forward.entry(name).or_default().insert(&limit.target);
+            backward.entry(&limit.target).or_default().insert(name);
+        }
+    }
+    for (group_name, group) in sm.loop_groups.iter() {
+        if !sm.states.contains_key(&group.limit.target) {
+            issues.push(LintIssue::error(
+                None,
+                format!("loop group {group_name} goes to unknown state {}", group.limit.target),
+            ));
+        }
+        for name in group.states.iter() {
+            if !sm.states.contains_key(name) {
+                issues.push(LintIssue::warning(
+                    None,
+                    format!("loop group {group_name} contains unknown state {name}"),
+                ));
+            }
+        }
+    }
+
+    for (watcher_name, w) in sm.watchers.iter() {
+        match &w.trigger {
+            StateMachineTrigger::Immediate | StateMachineTrigger::Timeout { .. } => {
+                issues.push(LintIssue::error(
+                    None,
+                    format!("watcher {watcher_name} needs a string, regex or composite trigger"),
+                ));
+            }
+            trigger => {
+                if let Err(e) = trigger.compile() {
+                    issues.push(LintIssue::error(
+                        None,
+                        format!("watcher {watcher_name} has an invalid trigger: {e}"),
+                    ));
+                }
+                if let StateMachineTrigger::Regex { options, .. } | StateMachineTrigger::String { options, .. } = trigger
+                    && options.ignore_echo
+                {
+                    issues.push(LintIssue::warning(
+                        None,
+                        format!("watcher {watcher_name} sets ignore_echo, which only applies to transitions"),
+                    ));
+                }
+                lint_window(None, trigger, &mut issues);
+            }
+        }
+        lint_actions(None, &w.actions, config, &mut issues);
+        if let Some(target) = &w.target {
+            if !sm.states.contains_key(target) {
+                issues.push(LintIssue::error(
+                    None,
+                    format!("watcher {watcher_name} goes to unknown state {target}"),
+                ));
+            }
+            for (name, origin) in sm.origins.iter() {
+                if w.scope.contains(origin) {
+                    forward.entry(name).or_default().insert(target);
+                    backward.entry(target).or_default().insert(name);
+                }
+            }
+        }
+    }
+
+    for (entry, (hook, next)) in sm.hook_returns.iter() {
+        if !reachable(entry, &forward).contains(next) {
+            issues.push(LintIssue::error(
+                Some(entry),
+                format!("handler of hook {hook} never reaches {next}, mark it terminal if it ends the job"),
+            ));
+        }
+    }
+
+    let from_init = reachable(INITIAL_STATE, &forward);
+    let to_end = reachable(END_STATE, &backward);
+    let after_end = reachable(END_STATE, &forward);
+    for name in sm.states.keys() {
+        if !from_init.contains(name) {
+            issues.push(LintIssue::warning(
+                Some(name),
+                "not reachable from Init".to_string(),
+            ));
+        }
+        if !to_end.contains(name) && !after_end.contains(name) {
+            issues.push(LintIssue::warning(
+                Some(name),
+                "no path to EndJob".to_string(),
+            ));
+        }
+    }
+
+    issues
+}
@@ -2,10 +2,10 @@ use crate::AngelJob;
 use color_eyre::eyre::Context;
 use cthulhu_common::devinfo::DeviceInformation;
 use regex::RegexBuilder;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use swexpect::SwitchExpect;
 
-#[derive(Deserialize, Clone, Debug, PartialOrd, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialOrd, PartialEq)]
 pub enum ProcessFunction {
     FixFS,
     CaptureJunosVersion,
@@ -62,7 +62,7 @@ impl ProcessFunction {
             ProcessFunction::CaptureJunosVersion => {
                 let r = RegexBuilder::new(r"(?:Model: (?<model>[a-zA-Z0-9\-]+)$)|(?:Junos: (?<version>[0-9a-zA-Z\-\.]+)$)|(?:JUNOS Base OS boot \[(?<version2>[0-9a-zA-Z\-\.]+)\]$)")
                     .multi_line(true).crlf(true).build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(model) = cap.name("model") {
                         job.add_information(DeviceInformation::Model(model.as_str().to_string()))
                             .await?;
@@ -87,7 +87,7 @@ impl ProcessFunction {
                     .multi_line(true)
                     .crlf(true)
                     .build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(serial) = cap.name("serial") {
                         job.add_information(DeviceInformation::SerialNumber(
                             serial.as_str().to_string(),
@@ -100,7 +100,7 @@ impl ProcessFunction {
             ProcessFunction::CaptureAristaVersion => {
                 let r = RegexBuilder::new(r"(?:^Arista (?<model>[a-zA-Z \-0-9]+)$)|(?:^Serial number:\s+(?<serial>[A-Za-z0-9]+)$)|(?:Software image version: (?<version>[0-9\.A-Za-z]+)$)")
                     .multi_line(true).crlf(true).build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(model) = cap.name("model") {
                         job.add_information(DeviceInformation::Model(model.as_str().to_string()))
                             .await?;
@@ -123,7 +123,7 @@ impl ProcessFunction {
             ProcessFunction::CaptureAristaAbootVersion => {
                 let r = RegexBuilder::new(r"(?<aboot>[\d\.-]+)$")
                     .multi_line(true).crlf(true).build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(version) = cap.name("aboot") {
                         job.add_information(DeviceInformation::BootloaderVersion(
                             version.as_str().to_string(),
@@ -138,7 +138,7 @@ impl ProcessFunction {
                     .multi_line(true)
                     .crlf(true)
                     .build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(model) = cap.name("model") {
                         job.add_information(DeviceInformation::Model(
                             model.as_str().to_string(),
@@ -153,7 +153,7 @@ impl ProcessFunction {
                     .multi_line(true)
                     .crlf(true)
                     .build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(serial) = cap.name("serial") {
                         job.add_information(DeviceInformation::SerialNumber(
                             serial.as_str().to_string(),
@@ -167,7 +167,7 @@ impl ProcessFunction {
                     .multi_line(true)
                     .crlf(true)
                     .build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(mac) = cap.name("mac") {
                         job.add_information(DeviceInformation::MacAddress(
                             mac.as_str().to_string(),
@@ -183,7 +183,7 @@ impl ProcessFunction {
                     .multi_line(true)
                     .crlf(true)
                     .build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(model) = cap.name("model") {
                         job.add_information(DeviceInformation::Model(
                             model.as_str().to_string(),
@@ -216,7 +216,7 @@ impl ProcessFunction {
                     .multi_line(true)
                     .crlf(true)
                     .build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(serial) = cap.name("serial") {
                         job.add_information(DeviceInformation::SerialNumber(
                             serial.as_str().to_string(),
@@ -231,7 +231,7 @@ impl ProcessFunction {
                     .multi_line(true)
                     .crlf(true)
                     .build()?;
-                for cap in r.captures_iter(&data) {
+                for cap in r.captures_iter(data) {
                     if let Some(version) = cap.name("version") {
                         job.add_information(DeviceInformation::SoftwareVersion(
                             version.as_str().to_string(),
@@ -0,0 +1,249 @@
+use crate::data_structure::State;
+use crate::runner::{DETECT_STATES, INITIAL_STATE};
+use crate::state::StateMachine;
+use color_eyre::eyre::{Context, eyre};
+use cthulhu_common::devinfo::{DeviceInformation, DeviceInformationKind};
+use regex::Regex;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+
+/// Which devices a profile applies to. Every rule that is set has to hold.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileRules {
+    pub vendor: Option<String>,
+    pub model: Option<Regex>,
+    pub serial_numbers: Vec<String>,
+}
+
+impl ProfileRules {
+    pub fn new(
+        vendor: Option<String>,
+        model: Option<&str>,
+        serial_numbers: Vec<String>,
+    ) -> color_eyre::Result<Self> {
+        let model = model
+            .map(|m| Regex::new(m).wrap_err_with(|| format!("invalid model regex {m:?}")))
+            .transpose()?;
+        Ok(Self {
+            vendor,
+            model,
+            serial_numbers,
+        })
+    }
+
+    /// The kinds of information the rules depend on.
+    pub fn kinds(&self) -> BTreeSet<DeviceInformationKind> {
+        let mut kinds = BTreeSet::new();
+        if self.vendor.is_some() {
+            kinds.insert(DeviceInformationKind::Vendor);
+        }
+        if self.model.is_some() {
+            kinds.insert(DeviceInformationKind::Model);
+        }
+        if !self.serial_numbers.is_empty() {
+            kinds.insert(DeviceInformationKind::SerialNumber);
+        }
+        kinds
+    }
+
+    /// Whether the rules hold for the recorded information, or `None` while a rule
+    /// depends on information that has not been recorded yet.
+    pub fn matches(&self, info: &[DeviceInformation]) -> Option<bool> {
+        let values = |kind: DeviceInformationKind| {
+            info.iter()
+                .filter_map(|i| i.value())
+                .filter(move |(k, _)| *k == kind)
+                .map(|(_, v)| v)
+                .collect::<Vec<_>>()
+        };
+        let rule = |kind, holds: &dyn Fn(&str) -> bool| {
+            let values = values(kind);
+            (!values.is_empty()).then(|| values.into_iter().any(holds))
+        };
+
+        let mut results = Vec::new();
+        if let Some(vendor) = &self.vendor {
+            results.push(rule(DeviceInformationKind::Vendor, &|v| {
+                v.eq_ignore_ascii_case(vendor)
+            }));
+        }
+        if let Some(model) = &self.model {
+            results.push(rule(DeviceInformationKind::Model, &|v| model.is_match(v)));
+        }
+        if !self.serial_numbers.is_empty() {
+            results.push(rule(DeviceInformationKind::SerialNumber, &|v| {
+                self.serial_numbers.iter().any(|s| s == v)
+            }));
+        }
+
+        if results.contains(&Some(false)) {
+            Some(false)
+        } else if results.contains(&None) {
+            None
+        } else {
+            Some(true)
+        }
+    }
+}
+
+/// The state machine to use for the devices matching the rules.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub rules: ProfileRules,
+    /// Record `DidNotWipe` and end the job instead of switching state machines.
+    pub skip: bool,
+    pub state_machine: Arc<StateMachine>,
+}
+
+/// The first profile whose rules hold. Nothing is selected while an earlier profile
+/// is still undecided, so that the order of the profiles is respected.
+pub fn select_profile<'a>(
+    profiles: &'a [Profile],
+    info: &[DeviceInformation],
+) -> Option<&'a Profile> {
+    for profile in profiles {
+        match profile.rules.matches(info) {
+            Some(true) => return Some(profile),
+            Some(false) => continue,
+            None => return None,
+        }
+    }
+    None
+}
+
+/// The states of `sm` the profile at `index` can be selected in: the states other than the
+/// detect states that are entered once the information its rules depend on was recorded,
+/// up to where the rules of the profiles before it can be decided as well. What is recorded
+/// is taken from the actions of the transitions and states on the way.
+pub fn selection_states(sm: &StateMachine, profiles: &[Profile], index: usize) -> BTreeSet<State> {
+    let kinds = profiles[index].rules.kinds();
+    let decided: BTreeSet<_> = profiles[..=index].iter().flat_map(|p| p.rules.kinds()).collect();
+    let mut states = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    let mut queue = VecDeque::from([(INITIAL_STATE.to_string(), BTreeSet::new())]);
+    while let Some((name, recorded)) = queue.pop_front() {
+        if !seen.insert((name.clone(), recorded.clone())) {
+            continue;
+        }
+        if !DETECT_STATES.contains(&name.as_str()) {
+            if recorded.is_superset(&kinds) {
+                states.insert(name.clone());
+            }
+            if recorded.is_superset(&decided) {
+                continue;
+            }
+        }
+        let Some(state) = sm.get_state(&name) else {
+            continue;
+        };
+
+        let transitions = state
+            .transitions
+            .iter()
+            .map(|t| (&t.target, t.actions.as_slice()))
+            .chain(state.limit.iter().map(|l| (&l.target, &[][..])));
+        for (target, actions) in transitions {
+            let mut recorded = recorded.clone();
+            let on_enter = sm.get_state(target).map(|s| s.on_enter.as_slice()).unwrap_or_default();
+            for action in state.on_exit.iter().chain(actions).chain(on_enter) {
+                action.recorded_kinds(&mut recorded);
+            }
+            queue.push_back((target.clone(), recorded));
+        }
+    }
+    states
+}
+
+/// Check that the state machine of every profile can take over from `sm` in all states
+/// the profile can be selected in.
+pub fn check_profiles(sm: &StateMachine, profiles: &[Profile]) -> color_eyre::Result<()> {
+    for (index, profile) in profiles.iter().enumerate().filter(|(_, p)| !p.skip) {
+        let missing: Vec<_> = selection_states(sm, profiles, index)
+            .into_iter()
+            .filter(|s| profile.state_machine.get_state(s).is_none())
+            .collect();
+        if !missing.is_empty() {
+            return Err(eyre!(
+                "profile {:?} can be selected in states it does not have: {missing:?}",
+                profile.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StateMachineBuilder;
+
+    fn profile(name: &str, rules: ProfileRules) -> Profile {
+        Profile {
+            name: name.to_string(),
+            rules,
+            skip: false,
+            state_machine: Arc::new(StateMachine::default()),
+        }
+    }
+
+    #[test]
+    fn selection() -> color_eyre::Result<()> {
+        let juniper = || Some("Juniper".to_string());
+        let profiles = vec![
+            profile(
+                "ex3300",
+                ProfileRules::new(juniper(), Some("(?i)^ex3300"), vec![])?,
+            ),
+            profile(
+                "ex2200",
+                ProfileRules::new(juniper(), Some("(?i)^ex2200"), vec![])?,
+            ),
+            profile(
+                "aruba",
+                ProfileRules::new(Some("aruba".to_string()), None, vec![])?,
+            ),
+            profile(
+                "lab",
+                ProfileRules::new(None, None, vec!["ABC123".to_string()])?,
+            ),
+        ];
+        let selected =
+            |info: &[DeviceInformation]| select_profile(&profiles, info).map(|p| p.name.as_str());
+
+        let mut info = vec![DeviceInformation::Vendor("Juniper".to_string())];
+        assert_eq!(selected(&info), None, "the model is not known yet");
+        info.push(DeviceInformation::Model("ex2200-24t-4g".to_string()));
+        assert_eq!(selected(&info), Some("ex2200"));
+        info[1] = DeviceInformation::Model("EX4200-48T".to_string());
+        assert_eq!(selected(&info), None, "the serial number is not known yet");
+        info.push(DeviceInformation::SerialNumber("ABC123".to_string()));
+        assert_eq!(selected(&info), Some("lab"));
+
+        assert_eq!(
+            selected(&[DeviceInformation::Vendor("Aruba".to_string())]),
+            Some("aruba")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn takeover_states() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        builder.active_all_state_files()?;
+        let sm = Arc::new(builder.build()?);
+
+        let rules = ProfileRules::new(Some("Arista".to_string()), Some("^DCS-7050"), vec![])?;
+        let mut profiles = vec![profile("arista", rules)];
+        // The model is recorded when leaving AristaVersionOutput.
+        let states = selection_states(&sm, &profiles, 0);
+        assert!(!states.contains("AristaVersionOutput"));
+        assert!(states.contains("AristaEnable"));
+        assert!(!states.contains("AristaEraseCores"));
+
+        assert!(check_profiles(&sm, &profiles).is_err());
+        profiles[0].state_machine = sm.clone();
+        check_profiles(&sm, &profiles)
+    }
+}
@@ -0,0 +1,321 @@
+use crate::AngelJob;
+use crate::data_structure::{State, StateMachineTest};
+use crate::runner::{FINISHED_STATE, StateMachineRunner};
+use crate::state::StateMachine;
+use color_eyre::eyre::eyre;
+use cthulhu_common::devinfo::DeviceInformation;
+use cthulhu_common::job::StageMetadata;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use swexpect::SwitchExpect;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::info;
+
+/// Size of the in-memory pipe between the recorded log and the state machine.
+/// Kept small so that data trickles in like it would on a real serial port.
+const REPLAY_CHUNK_SIZE: usize = 64;
+
+/// Outcome of replaying a recorded serial log through a state machine.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    /// States visited, oldest first.
+    pub states: Vec<State>,
+    /// Device information recorded, in order.
+    pub information: Vec<DeviceInformation>,
+    /// Variables set during the job.
+    pub variables: BTreeMap<String, String>,
+    /// Lines the state machine sent to the device.
+    pub sent: Vec<String>,
+    /// Why the replay stopped before the job finished, if it did.
+    pub error: Option<String>,
+}
+
+impl ReplayReport {
+    pub fn finished(&self) -> bool {
+        self.states.last().is_some_and(|s| s == FINISHED_STATE)
+    }
+}
+
+/// Job backing a replay, keeping everything the state machine records in memory.
+pub(crate) struct ReplayJob {
+    job_config: BTreeMap<String, String>,
+    information: Vec<DeviceInformation>,
+    variables: BTreeMap<String, String>,
+    last_sent_line: Option<String>,
+}
+
+impl ReplayJob {
+    pub(crate) fn new(job_config: BTreeMap<String, String>) -> Self {
+        ReplayJob {
+            job_config,
+            information: Vec::new(),
+            variables: BTreeMap::new(),
+            last_sent_line: None,
+        }
+    }
+}
+
+impl AngelJob for ReplayJob {
+    async fn init_job(&mut self) -> color_eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn finish_job(&mut self) -> color_eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> color_eyre::Result<()> {
+        self.information.clear();
+        self.variables.clear();
+        self.last_sent_line = None;
+        Ok(())
+    }
+
+    async fn enter_state(&mut self, _state: &str, _metadata: StageMetadata) -> color_eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn add_information(&mut self, information: DeviceInformation) -> color_eyre::Result<()> {
+        info!("Recorded new switch information: {information:?}");
+        self.information.retain(|i| !information.replaces(i));
+        self.information.push(information);
+        Ok(())
+    }
+
+    async fn get_information(&self) -> Vec<DeviceInformation> {
+        self.information.clone()
+    }
+
+    async fn get_job_config_key(&self, key: &str) -> Option<String> {
+        self.job_config.get(key).cloned()
+    }
+
+    async fn get_secret(&self, _name: &str) -> Option<String> {
+        // Recorded logs have secrets masked, so use the mask as the value.
+        Some("***".to_string())
+    }
+
+    async fn get_variables(&self) -> BTreeMap<String, String> {
+        self.variables.clone()
+    }
+
+    async fn set_variable(&mut self, name: &str, value: String) -> color_eyre::Result<()> {
+        self.variables.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    async fn record_sent_line(&mut self, line: &str) {
+        self.last_sent_line = Some(line.to_string());
+    }
+
+    async fn last_sent_line(&self) -> Option<String> {
+        self.last_sent_line.clone()
+    }
+
+    async fn clear_sent_line(&mut self) {
+        self.last_sent_line = None;
+    }
+}
+
+/// Feed a recorded raw serial log (as written by the angel) through a state machine,
+/// until the job finishes or the log runs out.
+pub async fn replay_log(
+    state_machine: Arc<StateMachine>,
+    log: &[u8],
+    job_config: BTreeMap<String, String>,
+) -> color_eyre::Result<ReplayReport> {
+    let (port, device) = tokio::io::duplex(REPLAY_CHUNK_SIZE);
+    let (mut device_rx, mut device_tx) = tokio::io::split(device);
+
+    let log = log.to_vec();
+    let writer = tokio::spawn(async move {
+        device_tx.write_all(&log).await?;
+        device_tx.shutdown().await
+    });
+    let reader = tokio::spawn(async move {
+        let mut sent = Vec::new();
+        device_rx.read_to_end(&mut sent).await.map(|_| sent)
+    });
+
+    let mut p = SwitchExpect::new(port, None);
+    let mut job = ReplayJob::new(job_config);
+    let mut runner = StateMachineRunner::new(state_machine);
+    runner.reset(&mut job).await?;
+
+    let mut error = None;
+    while runner.current_state() != FINISHED_STATE {
+        if let Err(e) = runner.step(&mut job, &mut p).await {
+            error = Some(format!("{e:#}"));
+            break;
+        }
+    }
+
+    // Closing our end of the pipe lets both tasks run to completion.
+    drop(p);
+    writer.abort();
+    let sent = reader.await??;
+
+    Ok(ReplayReport {
+        states: runner.history().to_vec(),
+        information: job.information,
+        variables: job.variables,
+        sent: String::from_utf8_lossy(&sent)
+            .lines()
+            .map(|l| l.trim_end_matches('\r').to_string())
+            .collect(),
+        error,
+    })
+}
+
+/// Replay the transcript of a state file test and check the outcome against its expectations.
+pub async fn run_test(state_machine: Arc<StateMachine>, test: &StateMachineTest) -> color_eyre::Result<()> {
+    let report = replay_log(state_machine, test.transcript.as_bytes(), test.job_config.clone()).await?;
+    let stopped = report
+        .error
+        .as_ref()
+        .map(|e| format!(" (replay stopped: {e})"))
+        .unwrap_or_default();
+
+    if report.states != test.states {
+        return Err(eyre!("expected states {:?}, got {:?}{stopped}", test.states, report.states));
+    }
+    if let Some(sent) = &test.sent
+        && *sent != report.sent
+    {
+        return Err(eyre!("expected sent lines {sent:?}, got {:?}{stopped}", report.sent));
+    }
+    if let Some(information) = &test.information
+        && *information != report.information
+    {
+        return Err(eyre!(
+            "expected information {information:?}, got {:?}{stopped}",
+            report.information
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StateMachineBuilder;
+
+    const TEST_STATES: &str = r#"
+id = "replay_test"
+
+watcher "DiskWarning" {
+  trigger {
+    type   = "string"
+    string = "WARNING: disk"
+  }
+  flag = "SCSIErrors"
+}
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "ReplayTestLogin"
+    trigger {
+      type   = "string"
+      string = "Replay Test Bootloader"
+    }
+    action {
+      type   = "AddDeviceInfo"
+      Vendor = "Test"
+    }
+  }
+}
+
+state "ReplayTestLogin" {
+  on_exit {
+    type = "SendLine"
+    line = ""
+  }
+  transition {
+    target = "ReplayTestShell"
+    trigger {
+      type   = "string"
+      string = "login:"
+    }
+    action {
+      type   = "Capture"
+      regex  = "^ (?<version>[0-9.]+)$"
+      groups = { version = "BootloaderVersion" }
+    }
+    action {
+      type = "SendLine"
+      line = "root"
+    }
+  }
+}
+
+state "ReplayTestShell" {
+  on_enter {
+    type = "SendLine"
+    line = "id"
+  }
+  transition {
+    target = "EndJob"
+    trigger {
+      type  = "regex"
+      regex = "root@[a-z]+#"
+    }
+    guard {
+      type  = "matches"
+      kind  = "Vendor"
+      regex = "^Other"
+    }
+    guard {
+      type = "not"
+      guard {
+        type = "has_flag"
+        flag = "Aborted"
+      }
+    }
+    action {
+      type = "AddDeviceInfo"
+      flag = "Aborted"
+    }
+  }
+  transition {
+    target = "EndJob"
+    trigger {
+      type  = "regex"
+      regex = "root@[a-z]+#"
+    }
+    action {
+      type = "AddDeviceInfo"
+      flag = "KeptHostname"
+    }
+  }
+}
+"#;
+
+    #[tokio::test]
+    async fn replay_transcript() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_state_file(hcl::from_str(TEST_STATES)?);
+        builder.activate_state_file("replay_test")?;
+        let sm = builder.build()?;
+
+        let log = "Replay Test Bootloader 1.0\r\nbooting...\r\nlogin: root\r\nWARNING: disk slow\r\nroot@switch# ";
+        let report = replay_log(Arc::new(sm), log.as_bytes(), BTreeMap::new()).await?;
+
+        assert!(report.finished(), "{report:?}");
+        assert_eq!(
+            report.states,
+            vec!["Init", "SwitchDetect", "ReplayTestLogin", "ReplayTestShell", "EndJob", "JobFinished"]
+        );
+        assert_eq!(report.sent, vec!["", "root", "id"]);
+        assert_eq!(
+            report.information,
+            vec![
+                DeviceInformation::Vendor("Test".to_string()),
+                DeviceInformation::BootloaderVersion("1.0".to_string()),
+                DeviceInformation::SCSIErrors,
+                DeviceInformation::KeptHostname,
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,524 @@
+use crate::AngelJob;
+use crate::action::Action;
+use crate::data_structure::{State, StateMachineLimit, StateMachineTransition};
+use crate::profile::{Profile, select_profile};
+use crate::state::StateMachine;
+use crate::template::TemplateError;
+use crate::trigger::{EchoRegexes, ReceiveHistory};
+use color_eyre::eyre::{Context, eyre};
+use cthulhu_common::devinfo::DeviceInformation;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use swexpect::SwitchExpect;
+use swexpect::hay::ReadUntil;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+pub const INITIAL_STATE: &str = "Init";
+pub const END_STATE: &str = "EndJob";
+pub const FINISHED_STATE: &str = "JobFinished";
+/// States in which the device is still being detected, so no profile is selected yet.
+pub const DETECT_STATES: &[&str] = &[INITIAL_STATE, "SwitchDetect"];
+
+fn guards_hold(
+    t: &StateMachineTransition,
+    info: &[DeviceInformation],
+    variables: &BTreeMap<String, String>,
+) -> color_eyre::Result<bool> {
+    for guard in t.guards.iter() {
+        if !guard.holds(info, variables)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Drives a job through a state machine, one serial match at a time.
+pub struct StateMachineRunner {
+    state_machine: Arc<StateMachine>,
+    /// The state machine jobs start with, until a profile is selected.
+    default_state_machine: Arc<StateMachine>,
+    profiles: Vec<Profile>,
+    profile: Option<String>,
+    current_state: State,
+    history: Vec<State>,
+    received: ReceiveHistory,
+    echo_regexes: EchoRegexes,
+    /// When the current state was entered, timeouts count from there.
+    entered: Instant,
+}
+
+impl StateMachineRunner {
+    pub fn new(state_machine: Arc<StateMachine>) -> Self {
+        Self {
+            default_state_machine: state_machine.clone(),
+            state_machine,
+            profiles: Vec::new(),
+            profile: None,
+            current_state: INITIAL_STATE.to_string(),
+            history: vec![INITIAL_STATE.to_string()],
+            received: ReceiveHistory::default(),
+            echo_regexes: EchoRegexes::default(),
+            entered: Instant::now(),
+        }
+    }
+
+    pub fn state_machine(&self) -> &StateMachine {
+        &self.state_machine
+    }
+
+    /// Switch to the state machine of the first matching profile once the device
+    /// has been detected. Until then, the machine given to `new` is used.
+    pub fn with_profiles(mut self, profiles: Vec<Profile>) -> Self {
+        self.profiles = profiles;
+        self
+    }
+
+    /// The profile selected for the current job, if any.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn current_state(&self) -> &str {
+        &self.current_state
+    }
+
+    /// States visited during the current job, oldest first.
+    pub fn history(&self) -> &[State] {
+        &self.history
+    }
+
+    /// Reset both the runner and the job, returning to the initial state.
+    pub async fn reset<T: AngelJob>(&mut self, job: &mut T) -> color_eyre::Result<()> {
+        self.current_state = INITIAL_STATE.to_string();
+        self.history = vec![INITIAL_STATE.to_string()];
+        self.entered = Instant::now();
+        self.received.clear();
+        self.state_machine = self.default_state_machine.clone();
+        self.profile = None;
+        job.reset().await?;
+        let metadata = self.state_machine.state(INITIAL_STATE)?.metadata();
+        job.enter_state(INITIAL_STATE, metadata).await?;
+        Ok(())
+    }
+
+    async fn enter_state<T: AngelJob>(&mut self, job: &mut T, state: &str) -> color_eyre::Result<()> {
+        self.current_state = state.to_string();
+        self.history.push(state.to_string());
+        self.entered = Instant::now();
+        let metadata = self.state_machine.state(state)?.metadata();
+        job.enter_state(state, metadata).await
+    }
+
+    async fn transition<T: AngelJob>(
+        &mut self,
+        job: &mut T,
+        t: &StateMachineTransition,
+        p: &mut SwitchExpect,
+        d: &str,
+        m: &str,
+    ) -> color_eyre::Result<()> {
+        info!("State transition: {:?} -> {:?}", self.current_state, t.target);
+        self.change_state(job, p, &t.target, &t.actions, d, m).await?;
+
+        if let Some(limit) = self.exceeded_limit()? {
+            warn!(
+                "Loop detected in {:?}! Going to {:?}...",
+                self.current_state, limit.target
+            );
+            job.add_information(limit.flag).await?;
+            self.change_state(job, p, &limit.target, &[], d, m).await?;
+        }
+        Ok(())
+    }
+
+    /// Move to `target`, performing the exit actions of the current state,
+    /// then `actions`, then the entry actions of the target.
+    async fn change_state<T: AngelJob>(
+        &mut self,
+        job: &mut T,
+        p: &mut SwitchExpect,
+        target: &str,
+        actions: &[Action],
+        d: &str,
+        m: &str,
+    ) -> color_eyre::Result<()> {
+        let sm = self.state_machine.clone();
+        // Validate that the state exists
+        let next = sm.state(target)?;
+
+        for action in sm.state(&self.current_state)?.on_exit.iter() {
+            action
+                .perform(job, p, d, m)
+                .await
+                .context("perform exit actions")?;
+        }
+        self.enter_state(job, target).await?;
+        for action in actions {
+            action.perform(job, p, d, m).await?;
+        }
+        for action in next.on_enter.iter() {
+            action
+                .perform(job, p, d, m)
+                .await
+                .context("perform entry actions")?;
+        }
+        Ok(())
+    }
+
+    /// Find a visit limit that the current state has exceeded, either its own or one of a loop group.
+    fn exceeded_limit(&self) -> color_eyre::Result<Option<StateMachineLimit>> {
+        let current = &self.current_state;
+        let visits = |states: &[State]| self.history.iter().filter(|s| states.contains(s)).count();
+
+        let limit = self
+            .state_machine
+            .state(current)?
+            .limit
+            .clone()
+            .unwrap_or_default();
+        if visits(std::slice::from_ref(current)) > limit.max_visits {
+            return Ok(Some(limit));
+        }
+        for (name, group) in self.state_machine.loop_groups() {
+            if group.states.contains(current) && visits(&group.states) > group.limit.max_visits {
+                warn!("Loop group {name} exceeded its limit.");
+                return Ok(Some(group.limit.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Select a profile based on what the job has recorded about the device so far.
+    async fn select_profile<T: AngelJob>(&mut self, job: &mut T, p: &mut SwitchExpect) -> color_eyre::Result<()> {
+        let detecting = DETECT_STATES.contains(&self.current_state.as_str());
+        if self.profile.is_some() || self.profiles.is_empty() || detecting {
+            return Ok(());
+        }
+        let info = job.get_information().await;
+        let Some(profile) = select_profile(&self.profiles, &info).cloned() else {
+            return Ok(());
+        };
+
+        info!("Selected profile {:?}.", profile.name);
+        self.profile = Some(profile.name.clone());
+        job.set_variable("profile", profile.name.clone()).await?;
+        if profile.skip {
+            warn!("Profile {:?} skips this device.", profile.name);
+            job.add_information(DeviceInformation::DidNotWipe).await?;
+            return self.change_state(job, p, END_STATE, &[], "", "").await;
+        }
+        if profile.state_machine.get_state(&self.current_state).is_none() {
+            warn!(
+                "Profile {:?} has no state {:?} to continue in, keeping the default state machine.",
+                profile.name, self.current_state
+            );
+            return Ok(());
+        }
+        self.state_machine = profile.state_machine;
+        Ok(())
+    }
+
+    /// Handle the next transition. A template that can not be filled in ends the job,
+    /// other errors are returned.
+    pub async fn step<T: AngelJob>(&mut self, job: &mut T, p: &mut SwitchExpect) -> color_eyre::Result<()> {
+        match self.step_inner(job, p).await {
+            Err(e) if e.chain().any(|c| c.is::<TemplateError>()) => {
+                error!("Ending the job in {:?}: {e:#}", self.current_state);
+                job.add_information(DeviceInformation::TemplateFailed).await?;
+                // Skip the exit actions, they might need the same missing value. The failing
+                // action may have been on the way to the end already.
+                if self.current_state == END_STATE {
+                    return Ok(());
+                }
+                self.enter_state(job, END_STATE).await
+            }
+            r => r,
+        }
+    }
+
+    async fn step_inner<T: AngelJob>(&mut self, job: &mut T, p: &mut SwitchExpect) -> color_eyre::Result<()> {
+        self.select_profile(job, p).await.context("select profile")?;
+        let sm = self.state_machine.clone();
+        let s = sm.compiled_state(&self.current_state)?;
+        let info = job.get_information().await;
+        let variables = job.get_variables().await;
+        let mut transitions = Vec::new();
+        for t in s.transitions.iter() {
+            if guards_hold(&t.transition, &info, &variables).context("evaluate transition guards")? {
+                transitions.push(t);
+            }
+        }
+
+        if let Some(t) = transitions.iter().find(|t| t.trigger.is_immediate()) {
+            self.transition(job, &t.transition, p, "", "")
+                .await
+                .context("process immediate transition")?;
+        } else {
+            // Triggers that ignore the echo only match after the last sent line came back.
+            let last_sent = job.last_sent_line().await;
+            let echo = transitions
+                .iter()
+                .map(|t| self.echo_regexes.get(&t.trigger, last_sent.as_deref()))
+                .collect::<color_eyre::Result<Vec<_>>>()?;
+
+            // Watchers go first, so that they win when several needles are in the buffer.
+            let needles: Vec<_> = s
+                .watchers
+                .iter()
+                .flat_map(|w| w.trigger.needles())
+                .chain(
+                    transitions
+                        .iter()
+                        .zip(echo.iter())
+                        .flat_map(|(t, e)| match e {
+                            Some(r) => vec![ReadUntil::Regex(r.clone())],
+                            None => t.trigger.needles(),
+                        }),
+                )
+                .collect();
+            // Steps that match without leaving the state do not restart the timeout.
+            let timeout = transitions
+                .iter()
+                .filter_map(|t| t.trigger.timeout().map(|d| (d, t)))
+                .min_by_key(|(d, _)| *d)
+                .map(|(d, t)| (d, (self.entered + d).saturating_duration_since(Instant::now()), t));
+
+            // Without needles nothing can match, so only a timeout can get us out of here.
+            if needles.is_empty() {
+                let Some((_, left, t)) = timeout else {
+                    return Err(eyre!(
+                        "no transition can be taken from state {:?}, the guards of all of them failed",
+                        self.current_state
+                    ));
+                };
+                tokio::time::sleep(left).await;
+                self.transition(job, &t.transition, p, "", "")
+                    .await
+                    .context("process timeout transition")?;
+                return Ok(());
+            }
+            let u = ReadUntil::Any(needles);
+
+            // Try to handle a result from the switches.
+            debug!("Waiting for needle {u:?}...");
+            let (d, m) = if let Some((duration, left, t)) = timeout {
+                match tokio::time::timeout(left, p.expect(&u)).await {
+                    Ok(r) => r.context("failed to read from serial port")?,
+                    Err(_) => {
+                        warn!("Nothing matched within {duration:?}, taking timeout transition.");
+                        self.transition(job, &t.transition, p, "", "")
+                            .await
+                            .context("process timeout transition")?;
+                        return Ok(());
+                    }
+                }
+            } else {
+                p.expect(&u)
+                    .await
+                    .context("failed to read from serial port")?
+            };
+            self.received.push(&d, &m);
+
+            for w in s
+                .watchers
+                .iter()
+                .filter(|w| w.trigger.matches_result(&m) && w.trigger.holds_in(&self.received))
+            {
+                warn!("Watcher {} fired in state {:?}.", w.name, self.current_state);
+                if let Some(flag) = &w.watcher.flag {
+                    job.add_information(flag.clone()).await?;
+                }
+                if let Some(target) = &w.watcher.target {
+                    let t = StateMachineTransition {
+                        target: target.clone(),
+                        trigger: w.watcher.trigger.clone(),
+                        guards: vec![],
+                        actions: w.watcher.actions.clone(),
+                    };
+                    self.transition(job, &t, p, &d, &m)
+                        .await
+                        .context("process watcher transition")?;
+                    return Ok(());
+                }
+                for action in w.watcher.actions.iter() {
+                    action
+                        .perform(job, p, &d, &m)
+                        .await
+                        .context("process watcher actions")?;
+                }
+            }
+            let matched = transitions.iter().zip(echo.iter()).find(|(t, e)| match e {
+                Some(r) => r.is_match(&m),
+                None => t.trigger.matches_result(&m) && t.trigger.holds_in(&self.received),
+            });
+            if let Some((t, e)) = matched {
+                if e.is_some() {
+                    job.clear_sent_line().await;
+                }
+                // The echo counts as data received before the trigger.
+                let (d, m) = match e.as_ref().and_then(|r| r.captures(&m)).and_then(|c| c.get(1)) {
+                    Some(g) => (format!("{d}{}", &m[..g.start()]), m[g.start()..].to_string()),
+                    None => (d, m),
+                };
+                self.transition(job, &t.transition, p, &d, &m)
+                    .await
+                    .context("process serial transition")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AngelJob;
+    use crate::builder::StateMachineBuilder;
+    use crate::replay::{ReplayJob, replay_log};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    const TIMEOUT_STATES: &str = r#"
+id = "timeout_test"
+
+watcher "SCSIErrors" {
+  trigger {
+    type   = "string"
+    string = "SCSI Status"
+  }
+  flag = "SCSIErrors"
+}
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "TimeoutTestWait"
+    trigger {
+      type   = "string"
+      string = "Timeout Test Bootloader"
+    }
+  }
+}
+
+state "TimeoutTestWait" {
+  transition {
+    target = "EndJob"
+    trigger {
+      type   = "string"
+      string = "Never Printed"
+    }
+  }
+  transition {
+    target = "EndJob"
+    trigger {
+      type     = "timeout"
+      duration = 0.5
+    }
+  }
+}
+"#;
+
+    #[tokio::test]
+    async fn watcher_does_not_restart_timeout() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_state_file(hcl::from_str(TIMEOUT_STATES)?);
+        builder.activate_state_file("timeout_test")?;
+        let sm = Arc::new(builder.build()?);
+
+        // The watcher keeps matching well within the timeout, for much longer than the timeout.
+        let (port, mut device) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            device.write_all(b"Timeout Test Bootloader\r\n").await?;
+            for _ in 0..60 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                device.write_all(b"SCSI Status\r\n").await?;
+            }
+            std::future::pending::<std::io::Result<()>>().await
+        });
+
+        let mut p = SwitchExpect::new(port, None);
+        let mut job = ReplayJob::new(BTreeMap::new());
+        let mut runner = StateMachineRunner::new(sm);
+        runner.reset(&mut job).await?;
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while runner.current_state() != END_STATE {
+                runner.step(&mut job, &mut p).await?;
+            }
+            color_eyre::Result::<()>::Ok(())
+        })
+        .await
+        .map_err(|_| eyre!("timeout transition was not taken"))??;
+        writer.abort();
+
+        assert_eq!(runner.history(), ["Init", "SwitchDetect", "TimeoutTestWait", "EndJob"]);
+        assert_eq!(job.get_information().await, vec![DeviceInformation::SCSIErrors]);
+        Ok(())
+    }
+
+    const LOOP_STATES: &str = r#"
+id = "loop_test"
+
+loop_group "PingPong" {
+  states     = ["LoopTestPing", "LoopTestPong"]
+  max_visits = 3
+  flag       = "BootLoop"
+}
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "LoopTestPing"
+    trigger {
+      type   = "string"
+      string = "start"
+    }
+  }
+}
+
+state "LoopTestPing" {
+  transition {
+    target = "LoopTestPong"
+    trigger {
+      type   = "string"
+      string = "ping"
+    }
+    action {
+      type = "IncrementVariable"
+      name = "pings"
+    }
+  }
+}
+
+state "LoopTestPong" {
+  transition {
+    target = "LoopTestPing"
+    trigger {
+      type   = "string"
+      string = "pong"
+    }
+  }
+}
+"#;
+
+    #[tokio::test]
+    async fn loop_group_limit() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_state_file(hcl::from_str(LOOP_STATES)?);
+        builder.activate_state_file("loop_test")?;
+        let sm = builder.build()?;
+
+        let log = "start ping pong ping pong ping pong";
+        let report = replay_log(Arc::new(sm), log.as_bytes(), BTreeMap::new()).await?;
+
+        assert!(report.finished(), "{report:?}");
+        assert_eq!(
+            report.states,
+            vec!["Init", "SwitchDetect", "LoopTestPing", "LoopTestPong", "LoopTestPing", "LoopTestPong", "EndJob", "JobFinished"]
+        );
+        assert_eq!(report.information, vec![DeviceInformation::BootLoop]);
+        assert_eq!(report.variables.get("pings").map(String::as_str), Some("2"));
+        Ok(())
+    }
+}
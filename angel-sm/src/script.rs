@@ -0,0 +1,283 @@
+use crate::AngelJob;
+use crate::action::WAIT_FOR_TIMEOUT;
+use color_eyre::eyre::eyre;
+use cthulhu_common::devinfo::{DeviceInformation, DeviceInformationKind};
+use regex::Regex;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+use std::time::Duration;
+use swexpect::SwitchExpect;
+use swexpect::hay::ReadUntil;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// Operations a script may perform before it is stopped, so a runaway loop can not hang the job.
+const MAX_OPERATIONS: u64 = 10_000_000;
+/// How long a script may run, including the time spent waiting for the device.
+pub const SCRIPT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Something a script asks the job or the serial port to do.
+#[derive(Debug)]
+enum ScriptCall {
+    Send(String),
+    SendLine(String),
+    /// Returns everything received before the needle.
+    WaitFor(String),
+    AddInformation(DeviceInformation),
+    Config(String),
+    Variable(String),
+    SetVariable(String, String),
+}
+
+type ScriptReply = Result<Option<String>, String>;
+
+/// The script's end of the channel to the job. Scripts run on a blocking thread,
+/// so every call blocks until the job has handled it.
+#[derive(Clone)]
+struct ScriptBridge(mpsc::Sender<(ScriptCall, oneshot::Sender<ScriptReply>)>);
+
+impl ScriptBridge {
+    fn call(&self, call: ScriptCall) -> Result<Option<String>, Box<EvalAltResult>> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .blocking_send((call, tx))
+            .map_err(|_| "the job is no longer running")?;
+        rx.blocking_recv()
+            .map_err(|_| "the job is no longer running")?
+            .map_err(Into::into)
+    }
+}
+
+fn optional(value: Option<String>) -> Dynamic {
+    value.map(Dynamic::from).unwrap_or(Dynamic::UNIT)
+}
+
+/// All matches of `regex` in `text`, as maps of the named groups.
+fn captures(text: &str, regex: &str) -> Result<Array, Box<EvalAltResult>> {
+    let r = crate::action::capture_regex(regex).map_err(|e| e.to_string())?;
+    Ok(r.captures_iter(text)
+        .map(|cap| {
+            let mut m = Map::new();
+            for name in r.capture_names().flatten() {
+                if let Some(v) = cap.name(name) {
+                    m.insert(name.into(), v.as_str().to_string().into());
+                }
+            }
+            Dynamic::from_map(m)
+        })
+        .collect())
+}
+
+/// The engine with the script API. Without a bridge the API can only be compiled against.
+fn engine(bridge: Option<ScriptBridge>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|s| info!("script: {s}"));
+    engine.on_debug(|s, _, pos| info!("script ({pos}): {s}"));
+    engine.register_fn("captures", captures);
+    engine.register_fn(
+        "is_match",
+        |text: &str, regex: &str| -> Result<bool, Box<EvalAltResult>> {
+            Ok(Regex::new(regex).map_err(|e| e.to_string())?.is_match(text))
+        },
+    );
+
+    let Some(bridge) = bridge else {
+        return engine;
+    };
+    let b = bridge.clone();
+    engine.register_fn("send", move |text: &str| {
+        b.call(ScriptCall::Send(text.to_string())).map(|_| ())
+    });
+    let b = bridge.clone();
+    engine.register_fn("send_line", move |line: &str| {
+        b.call(ScriptCall::SendLine(line.to_string())).map(|_| ())
+    });
+    let b = bridge.clone();
+    engine.register_fn("wait_for", move |needle: &str| {
+        b.call(ScriptCall::WaitFor(needle.to_string()))
+            .map(Option::unwrap_or_default)
+    });
+    let b = bridge.clone();
+    engine.register_fn("add_info", move |kind: &str, value: &str| {
+        let kind: DeviceInformationKind = kind
+            .parse()
+            .map_err(|e| format!("unknown kind {kind}: {e}"))?;
+        b.call(ScriptCall::AddInformation(
+            kind.with_value(value.to_string()),
+        ))
+        .map(|_| ())
+    });
+    let b = bridge.clone();
+    engine.register_fn("add_flag", move |flag: &str| {
+        let flag: DeviceInformation =
+            serde_json::from_value(serde_json::Value::String(flag.to_string()))
+                .map_err(|e| format!("unknown flag {flag}: {e}"))?;
+        b.call(ScriptCall::AddInformation(flag)).map(|_| ())
+    });
+    let b = bridge.clone();
+    engine.register_fn("config", move |key: &str| {
+        b.call(ScriptCall::Config(key.to_string())).map(optional)
+    });
+    let b = bridge.clone();
+    engine.register_fn("get_var", move |name: &str| {
+        b.call(ScriptCall::Variable(name.to_string())).map(optional)
+    });
+    let b = bridge;
+    engine.register_fn("set_var", move |name: &str, value: Dynamic| {
+        b.call(ScriptCall::SetVariable(name.to_string(), value.to_string()))
+            .map(|_| ())
+    });
+    engine
+}
+
+/// Check that a script compiles.
+pub fn check_script(source: &str) -> color_eyre::Result<()> {
+    engine(None)
+        .compile(source)
+        .map(|_| ())
+        .map_err(|e| eyre!("{e}"))
+}
+
+async fn handle_call<T: AngelJob>(
+    call: ScriptCall,
+    job: &mut T,
+    p: &mut SwitchExpect,
+) -> color_eyre::Result<Option<String>> {
+    match call {
+        ScriptCall::Send(text) => {
+            p.send(&text).await?;
+            job.record_sent_line(&text).await;
+            Ok(None)
+        }
+        ScriptCall::SendLine(line) => {
+            p.send_line(&line).await?;
+            job.record_sent_line(&line).await;
+            Ok(None)
+        }
+        ScriptCall::WaitFor(needle) => {
+            let until = ReadUntil::String(needle.clone());
+            let (d, _) = tokio::time::timeout(WAIT_FOR_TIMEOUT, p.expect(&until))
+                .await
+                .map_err(|_| eyre!("{needle:?} was not received within {WAIT_FOR_TIMEOUT:?}"))??;
+            Ok(Some(d))
+        }
+        ScriptCall::AddInformation(information) => {
+            job.add_information(information).await?;
+            Ok(None)
+        }
+        ScriptCall::Config(key) => Ok(job.get_job_config_key(&key).await),
+        ScriptCall::Variable(name) => Ok(job.get_variables().await.remove(&name)),
+        ScriptCall::SetVariable(name, value) => {
+            job.set_variable(&name, value).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Run a script on a blocking thread, handling its calls to the job and serial port
+/// until it finishes. `data` and `matched` are available to the script as constants.
+pub async fn run_script<T: AngelJob>(
+    name: &str,
+    source: &str,
+    job: &mut T,
+    p: &mut SwitchExpect,
+    data: &str,
+    mat: &str,
+) -> color_eyre::Result<()> {
+    let (tx, mut rx) = mpsc::channel(1);
+    let source = source.to_string();
+    let (data, mat) = (data.to_string(), mat.to_string());
+    let script = tokio::task::spawn_blocking(move || {
+        let engine = engine(Some(ScriptBridge(tx)));
+        let mut scope = Scope::new();
+        scope.push_constant("data", data);
+        scope.push_constant("matched", mat);
+        engine
+            .run_with_scope(&mut scope, &source)
+            .map_err(|e| e.to_string())
+    });
+
+    let calls = async {
+        while let Some((call, reply)) = rx.recv().await {
+            let result = handle_call(call, job, p)
+                .await
+                .map_err(|e| format!("{e:#}"));
+            if let Err(e) = &result {
+                warn!("Call from script {name} failed: {e}");
+            }
+            // The script only goes away once it failed, which it reports below.
+            let _ = reply.send(result);
+        }
+    };
+    // Dropping the channel makes the next call of a script that timed out fail.
+    tokio::time::timeout(SCRIPT_TIMEOUT, calls)
+        .await
+        .map_err(|_| eyre!("script {name} did not finish within {SCRIPT_TIMEOUT:?}"))?;
+
+    script
+        .await?
+        .map_err(|e| eyre!("script {name} failed: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::StateMachineBuilder;
+    use crate::replay::replay_log;
+    use cthulhu_common::devinfo::DeviceInformation;
+    use rhai::EvalAltResult;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    const SCRIPT_STATES: &str = r#"
+id = "script_test"
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "EndJob"
+    trigger {
+      type   = "string"
+      string = "ufs check failed"
+    }
+    action {
+      type   = "Script"
+      script = "fix_fs"
+    }
+  }
+}
+"#;
+
+    #[tokio::test]
+    async fn fix_fs_script() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_builtin_state_files()?;
+        builder.load_state_file(hcl::from_str(SCRIPT_STATES)?);
+        builder.activate_state_file("script_test")?;
+        let sm = builder.build()?;
+
+        let log = "ufs: /dev/da0s1a (/)\r\nufs: /dev/da0s1e (/config)\r\nufs check failed\r\n# \r\n# \r\n# \r\n";
+        let report = replay_log(Arc::new(sm), log.as_bytes(), BTreeMap::new()).await?;
+
+        assert!(report.finished(), "{report:?}");
+        assert_eq!(
+            report.sent,
+            vec![
+                "shell",
+                "fsck -y /dev/da0s1a",
+                "fsck -y /dev/da0s1e",
+                "reboot"
+            ]
+        );
+        assert_eq!(
+            report.information,
+            vec![DeviceInformation::AttemptedToFixFilesystemIssues]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn runaway_script() {
+        let result = super::engine(None).run("loop {}");
+        assert!(result.is_err_and(|e| matches!(*e, EvalAltResult::ErrorTooManyOperations(_))));
+    }
+}
@@ -1,27 +1,95 @@
 use crate::action::Action;
 use crate::data_structure::{
-    StateMachineMergeMode, StateMachineState, StateMachineTransition, StateMachineTrigger, StateMap,
+    State, StateMachineCall, StateMachineHook, StateMachineHookHandler, StateMachineLoopGroup,
+    StateMachineMergeMode, StateMachineRoutine, StateMachineState, StateMachineTransition,
+    StateMachineTrigger, StateMachineWatcher, StateMap,
 };
-use color_eyre::eyre::eyre;
+use crate::trigger::CompiledTrigger;
+use color_eyre::eyre::{WrapErr, eyre};
+use cthulhu_common::job::JobPhase;
+use serde::Serialize;
+use std::collections::BTreeMap;
 
-#[derive(Debug)]
+/// Prefix of transition targets that hand control back to a hook, as in `return:HookJunosCLI`.
+pub const HOOK_RETURN_PREFIX: &str = "return:";
+
+/// A transition with its trigger compiled.
+#[derive(Clone, Debug)]
+pub struct CompiledTransition {
+    pub trigger: CompiledTrigger,
+    pub transition: StateMachineTransition,
+}
+
+/// A watcher with its trigger compiled.
+#[derive(Clone, Debug)]
+pub struct CompiledWatcher {
+    pub name: String,
+    pub trigger: CompiledTrigger,
+    pub watcher: StateMachineWatcher,
+}
+
+/// A state as used while running a job.
+#[derive(Clone, Debug)]
+pub struct CompiledState {
+    pub transitions: Vec<CompiledTransition>,
+    /// Watchers whose scope includes this state.
+    pub watchers: Vec<CompiledWatcher>,
+}
+
+/// The merged state machine. Once built it is immutable; share it behind an `Arc`.
+/// It serializes with the keys of a state file, without the compiled triggers.
+#[derive(Clone, Debug, Serialize)]
 pub struct StateMachine {
+    #[serde(rename = "state")]
     pub(crate) states: StateMap,
+    #[serde(rename = "loop_group")]
+    pub(crate) loop_groups: BTreeMap<String, StateMachineLoopGroup>,
+    #[serde(rename = "watcher")]
+    pub(crate) watchers: BTreeMap<String, StateMachineWatcher>,
+    /// The state file each state was defined in, or last replaced by.
+    #[serde(rename = "origin")]
+    pub(crate) origins: BTreeMap<State, String>,
+    #[serde(skip)]
+    compiled: BTreeMap<State, CompiledState>,
+    #[serde(skip)]
+    pub(crate) fingerprint: String,
+    /// Ids of the state files the machine was built from, in merge order.
+    #[serde(skip)]
+    pub(crate) active_files: Vec<String>,
+    /// Entry states of hook handlers that hand control back, with the hook and the step
+    /// they return to, for linting.
+    #[serde(skip)]
+    pub(crate) hook_returns: BTreeMap<State, (String, State)>,
 }
 
 impl Default for StateMachine {
     fn default() -> Self {
         let mut s = Self {
             states: StateMap::new(),
+            loop_groups: BTreeMap::new(),
+            watchers: BTreeMap::new(),
+            origins: BTreeMap::new(),
+            compiled: BTreeMap::new(),
+            fingerprint: String::new(),
+            active_files: Vec::new(),
+            hook_returns: BTreeMap::new(),
         };
 
         s.states.insert(
             "Init".to_string(),
             StateMachineState {
                 merge: Default::default(),
+                call: None,
+                limit: None,
+                on_enter: vec![],
+                on_exit: vec![],
+                description: Some("Waiting for a switch".to_string()),
+                phase: Some(JobPhase::Detect),
+                progress: Some(0),
                 transitions: vec![StateMachineTransition {
                     target: "SwitchDetect".to_string(),
                     trigger: StateMachineTrigger::Immediate,
+                    guards: vec![],
                     actions: vec![Action::SetupJob],
                 }],
             },
@@ -31,11 +99,20 @@ impl Default for StateMachine {
             "SwitchDetect".to_string(),
             StateMachineState {
                 merge: Default::default(),
+                call: None,
+                limit: None,
+                on_enter: vec![],
+                on_exit: vec![],
+                description: Some("Detecting the switch".to_string()),
+                phase: Some(JobPhase::Detect),
+                progress: Some(0),
                 transitions: vec![StateMachineTransition {
                     target: "SwitchDetect".to_string(),
                     trigger: StateMachineTrigger::String {
                         string: "A non-empty Data Buffering File was found.".to_string(),
+                        options: Default::default(),
                     },
+                    guards: vec![],
                     actions: vec![Action::SendLine {
                         line: "E".to_string(),
                     }],
@@ -47,9 +124,17 @@ impl Default for StateMachine {
             "EndJob".to_string(),
             StateMachineState {
                 merge: Default::default(),
+                call: None,
+                limit: None,
+                on_enter: vec![],
+                on_exit: vec![],
+                description: Some("Finishing up".to_string()),
+                phase: None,
+                progress: Some(100),
                 transitions: vec![StateMachineTransition {
                     target: "JobFinished".to_string(),
                     trigger: StateMachineTrigger::Immediate,
+                    guards: vec![],
                     actions: vec![Action::FinishJob],
                 }],
             },
@@ -59,11 +144,20 @@ impl Default for StateMachine {
             "JobFinished".to_string(),
             StateMachineState {
                 merge: Default::default(),
+                call: None,
+                limit: None,
+                on_enter: vec![],
+                on_exit: vec![],
+                description: Some("Done, replace the switch".to_string()),
+                phase: None,
+                progress: Some(100),
                 transitions: vec![StateMachineTransition {
                     target: "JobFinished".to_string(),
                     trigger: StateMachineTrigger::String {
                         string: "AAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+                        options: Default::default(),
                     },
+                    guards: vec![],
                     actions: vec![],
                 }],
             },
@@ -74,35 +168,397 @@ impl Default for StateMachine {
 }
 
 impl StateMachine {
-    pub fn merge_states(&mut self, states: StateMap) {
+    /// Merge states from the state file `origin` into the machine, returning the existing
+    /// states that got replaced.
+    pub fn merge_states(&mut self, origin: &str, states: StateMap) -> Vec<State> {
+        let mut replaced = Vec::new();
         for (key, value) in states {
             if let Some(v) = self.states.get_mut(&key) {
                 match value.merge {
                     StateMachineMergeMode::Replace => {
                         *v = value;
+                        self.origins.insert(key.clone(), origin.to_string());
+                        replaced.push(key);
                     }
                     StateMachineMergeMode::Append => {
                         v.transitions.extend(value.transitions);
+                        v.on_enter.extend(value.on_enter);
+                        v.on_exit.extend(value.on_exit);
+                        if value.call.is_some() {
+                            v.call = value.call;
+                        }
+                        if value.limit.is_some() {
+                            v.limit = value.limit;
+                        }
+                        if value.description.is_some() {
+                            v.description = value.description;
+                        }
+                        if value.phase.is_some() {
+                            v.phase = value.phase;
+                        }
+                        if value.progress.is_some() {
+                            v.progress = value.progress;
+                        }
                     }
                 }
             } else {
+                self.origins.insert(key.clone(), origin.to_string());
                 self.states.insert(key, value);
             }
         }
+        replaced
     }
     
+    /// Replace all routine calls with one state per command. The calling state sends the
+    /// first command, the following ones are sent from states named `{state}_{routine}{n}`.
+    pub fn expand_calls(
+        &mut self,
+        routines: &BTreeMap<String, StateMachineRoutine>,
+    ) -> color_eyre::Result<()> {
+        let calls: Vec<(State, StateMachineCall)> = self
+            .states
+            .iter_mut()
+            .filter_map(|(name, state)| state.call.take().map(|c| (name.clone(), c)))
+            .collect();
+
+        for (name, call) in calls {
+            // Steps are described like the calling state.
+            let metadata = self.states[&name].metadata();
+            let routine = routines
+                .get(&call.routine)
+                .ok_or_else(|| eyre!("state {name} calls unknown routine {}", call.routine))?;
+
+            let step_name = |i: usize| {
+                if i == 0 {
+                    name.clone()
+                } else if i < call.commands.len() {
+                    format!("{name}_{}{i}", call.routine)
+                } else {
+                    call.return_to.clone()
+                }
+            };
+
+            for i in 0..call.commands.len().max(1) {
+                let mut transitions = vec![StateMachineTransition {
+                    target: step_name(i + 1),
+                    trigger: routine.prompt.clone(),
+                    guards: vec![],
+                    actions: call
+                        .commands
+                        .get(i)
+                        .map(|c| Action::SendLine { line: c.clone() })
+                        .into_iter()
+                        .collect(),
+                }];
+                transitions.extend(routine.transitions.iter().cloned());
+
+                if i == 0 {
+                    self.states
+                        .get_mut(&name)
+                        .expect("calling state exists")
+                        .transitions
+                        .extend(transitions);
+                } else {
+                    let step = step_name(i);
+                    if self.states.contains_key(&step) {
+                        return Err(eyre!("routine step {step} clashes with an existing state"));
+                    }
+                    if let Some(origin) = self.origins.get(&name).cloned() {
+                        self.origins.insert(step.clone(), origin);
+                    }
+                    self.states.insert(
+                        step,
+                        StateMachineState {
+                            merge: Default::default(),
+                            transitions,
+                            call: None,
+                            limit: None,
+                            on_enter: vec![],
+                            on_exit: vec![],
+                            description: metadata.description.clone(),
+                            phase: metadata.phase,
+                            progress: metadata.progress,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn hooks into a chain of states: the hook itself goes to the first handler, and each
+    /// handler's `return:<hook>` transitions go to the next one, until the continuation.
+    /// A terminal handler has to be the last one, and leaves out the continuation.
+    /// `hooks` holds the file declaring each hook, `handlers` the file of each handler.
+    pub(crate) fn expand_hooks(
+        &mut self,
+        hooks: &BTreeMap<String, (String, StateMachineHook)>,
+        handlers: &[(String, String, StateMachineHookHandler)],
+    ) -> color_eyre::Result<()> {
+        if let Some((hook, file, _)) = handlers.iter().find(|(hook, ..)| !hooks.contains_key(hook)) {
+            return Err(eyre!("state file {file} handles unknown hook {hook}"));
+        }
+
+        for (name, (origin, hook)) in hooks.iter() {
+            let mut chain: Vec<(&String, &StateMachineHookHandler)> = handlers
+                .iter()
+                .filter(|(h, ..)| h == name)
+                .map(|(_, file, handler)| (file, handler))
+                .collect();
+            chain.sort_by(|(fa, a), (fb, b)| a.priority.cmp(&b.priority).then_with(|| fa.cmp(fb)));
+            if let Some((file, _)) = chain.iter().rev().skip(1).find(|(_, h)| h.terminal) {
+                return Err(eyre!(
+                    "handler of hook {name} in {file} is terminal, but other handlers run after it"
+                ));
+            }
+            let terminal = chain.last().is_some_and(|(_, h)| h.terminal);
+
+            let step_name = |i: usize| if i == 0 { name.clone() } else { format!("{name}{i}") };
+            let steps = if terminal { chain.len() } else { chain.len() + 1 };
+            for i in 0..steps {
+                let step = step_name(i);
+                if self.states.contains_key(&step) {
+                    return Err(eyre!("hook step {step} clashes with an existing state"));
+                }
+                let (target, actions) = match chain.get(i) {
+                    Some((_, handler)) => (handler.entry.clone(), handler.actions.clone()),
+                    None => (hook.continuation.clone(), hook.actions.clone()),
+                };
+                self.origins.insert(step.clone(), origin.clone());
+                self.states.insert(
+                    step,
+                    StateMachineState {
+                        merge: Default::default(),
+                        transitions: vec![StateMachineTransition {
+                            target,
+                            trigger: StateMachineTrigger::Immediate,
+                            guards: vec![],
+                            actions,
+                        }],
+                        call: None,
+                        limit: None,
+                        on_enter: vec![],
+                        on_exit: vec![],
+                        description: None,
+                        phase: None,
+                        progress: None,
+                    },
+                );
+            }
+
+            let ret = format!("{HOOK_RETURN_PREFIX}{name}");
+            for (i, (file, handler)) in chain.iter().enumerate() {
+                let next = step_name(i + 1);
+                for (state_name, state) in self.states.iter_mut() {
+                    if self.origins.get(state_name) != Some(*file) {
+                        continue;
+                    }
+                    let returns = state.transitions.iter().any(|t| t.target == ret)
+                        || state.limit.as_ref().is_some_and(|l| l.target == ret);
+                    if returns && handler.terminal {
+                        return Err(eyre!(
+                            "state {state_name} returns to hook {name}, but its handler is terminal"
+                        ));
+                    }
+                    for t in state.transitions.iter_mut().filter(|t| t.target == ret) {
+                        t.target = next.clone();
+                    }
+                    if let Some(limit) = state.limit.as_mut()
+                        && limit.target == ret
+                    {
+                        limit.target = next.clone();
+                    }
+                }
+                if !handler.terminal {
+                    self.hook_returns.insert(handler.entry.clone(), (name.clone(), next));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in the source of every script and table template used by actions from the
+    /// loaded files. Unknown ones are left empty, for the lints to report.
+    pub(crate) fn resolve_files(
+        &mut self,
+        scripts: &BTreeMap<String, String>,
+        templates: &BTreeMap<String, String>,
+    ) {
+        fn resolve(
+            actions: &mut [Action],
+            scripts: &BTreeMap<String, String>,
+            templates: &BTreeMap<String, String>,
+        ) {
+            for action in actions.iter_mut() {
+                match action {
+                    Action::Script { script, source } => {
+                        if let Some(s) = scripts.get(script) {
+                            *source = s.clone();
+                        }
+                    }
+                    Action::ParseTable {
+                        template,
+                        template_source,
+                        ..
+                    } => {
+                        if let Some(s) = templates.get(template) {
+                            *template_source = s.clone();
+                        }
+                    }
+                    Action::Repeat { actions, .. } | Action::ForEach { actions, .. } => {
+                        resolve(actions, scripts, templates)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let resolve_all = |actions: &mut [Action]| resolve(actions, scripts, templates);
+
+        for state in self.states.values_mut() {
+            resolve_all(&mut state.on_enter);
+            resolve_all(&mut state.on_exit);
+            for t in state.transitions.iter_mut() {
+                resolve_all(&mut t.actions);
+            }
+        }
+        for w in self.watchers.values_mut() {
+            resolve_all(&mut w.actions);
+        }
+    }
+
+    /// The sources of the scripts and table templates used by actions, by name.
+    pub fn used_files(&self) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+        fn collect(
+            actions: &[Action],
+            scripts: &mut BTreeMap<String, String>,
+            templates: &mut BTreeMap<String, String>,
+        ) {
+            for action in actions.iter() {
+                match action {
+                    Action::Script { script, source } => {
+                        scripts.insert(script.clone(), source.clone());
+                    }
+                    Action::ParseTable {
+                        template,
+                        template_source,
+                        ..
+                    } => {
+                        templates.insert(template.clone(), template_source.clone());
+                    }
+                    Action::Repeat { actions, .. } | Action::ForEach { actions, .. } => {
+                        collect(actions, scripts, templates)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let (mut scripts, mut templates) = (BTreeMap::new(), BTreeMap::new());
+        for state in self.states.values() {
+            collect(&state.on_enter, &mut scripts, &mut templates);
+            collect(&state.on_exit, &mut scripts, &mut templates);
+            for t in state.transitions.iter() {
+                collect(&t.actions, &mut scripts, &mut templates);
+            }
+        }
+        for w in self.watchers.values() {
+            collect(&w.actions, &mut scripts, &mut templates);
+        }
+        (scripts, templates)
+    }
+
     pub fn states(&self) -> Vec<String> {
         self.states.keys().cloned().collect::<Vec<String>>()
     }
 
-    pub fn get_state(&self, key: &str) -> Option<StateMachineState> {
-        self.states.get(key).cloned()
+    pub fn loop_groups(&self) -> &BTreeMap<String, StateMachineLoopGroup> {
+        &self.loop_groups
+    }
+
+    pub fn watchers(&self) -> &BTreeMap<String, StateMachineWatcher> {
+        &self.watchers
+    }
+
+    /// SHA-256 of the merged machine and the scripts and templates it can use, in hex.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
     }
 
-    pub fn state(&self, key: &str) -> color_eyre::Result<StateMachineState> {
+    pub fn active_files(&self) -> &[String] {
+        &self.active_files
+    }
+
+    /// The state file a state came from. The builtin states have none.
+    pub fn origin(&self, state: &str) -> Option<&str> {
+        self.origins.get(state).map(|s| s.as_str())
+    }
+
+    pub fn get_state(&self, key: &str) -> Option<&StateMachineState> {
+        self.states.get(key)
+    }
+
+    pub fn state(&self, key: &str) -> color_eyre::Result<&StateMachineState> {
         self.states
             .get(key)
-            .cloned()
             .ok_or_else(|| eyre!("unknown state: {}", key))
     }
+
+    pub fn compiled_state(&self, key: &str) -> color_eyre::Result<&CompiledState> {
+        self.compiled
+            .get(key)
+            .ok_or_else(|| eyre!("unknown state: {}", key))
+    }
+
+    /// Compile the triggers of all states. Called once when building the state machine.
+    pub(crate) fn compile(&mut self) -> color_eyre::Result<()> {
+        let watchers = self
+            .watchers
+            .iter()
+            .map(|(name, w)| {
+                Ok(CompiledWatcher {
+                    name: name.clone(),
+                    trigger: w
+                        .trigger
+                        .compile()
+                        .wrap_err_with(|| format!("failed to compile watcher {name}"))?,
+                    watcher: w.clone(),
+                })
+            })
+            .collect::<color_eyre::Result<Vec<_>>>()?;
+
+        self.compiled = self
+            .states
+            .iter()
+            .map(|(name, state)| {
+                let transitions = state
+                    .transitions
+                    .iter()
+                    .map(|t| {
+                        Ok(CompiledTransition {
+                            trigger: t.trigger.compile()?,
+                            transition: t.clone(),
+                        })
+                    })
+                    .collect::<color_eyre::Result<Vec<_>>>()
+                    .wrap_err_with(|| format!("failed to compile state {name}"))?;
+                let watchers = watchers
+                    .iter()
+                    .filter(|w| {
+                        self.origins
+                            .get(name)
+                            .is_some_and(|o| w.watcher.scope.contains(o))
+                    })
+                    .cloned()
+                    .collect();
+                Ok((
+                    name.clone(),
+                    CompiledState {
+                        transitions,
+                        watchers,
+                    },
+                ))
+            })
+            .collect::<color_eyre::Result<_>>()?;
+        Ok(())
+    }
 }
@@ -32,6 +32,9 @@ state "SwitchDetect" {
 }
 
 state "AristaWaitForBootloader" {
+  description = "Waiting for the bootloader"
+  phase       = "detect"
+  progress    = 5
   transition {
     target = "AristaWipeStartupConfig"
     trigger {
@@ -39,8 +42,8 @@ state "AristaWaitForBootloader" {
       string = "Press"
     }
     action {
-      type = "Function"
-      func = "CaptureAristaAbootVersion"
+      type  = "Capture"
+      regex = "(?<BootloaderVersion>[\\d\\.-]+)$"
     }
     action {
       type = "SendControl"
@@ -55,8 +58,8 @@ state "AristaWaitForBootloader" {
       string = "Aboot#"
     }
     action {
-      type = "Function"
-      func = "CaptureAristaAbootVersion"
+      type  = "Capture"
+      regex = "(?<BootloaderVersion>[\\d\\.-]+)$"
     }
     action {
       type = "SendLine"
@@ -66,6 +69,9 @@ state "AristaWaitForBootloader" {
 }
 
 state "AristaWipeStartupConfig" {
+  description = "Removing the startup config"
+  phase       = "wipe"
+  progress    = 20
   transition {
     target = "AristaRebootAfterStartupConfigWipe"
     trigger {
@@ -94,19 +100,16 @@ state "AristaRebootAfterStartupConfigWipe" {
   transition {
     target = "AristaBootloaderHook"
     trigger {
-      type   = "string"
-      string = "Aboot#"
+      type        = "string"
+      string      = "Aboot#"
+      ignore_echo = true
     }
   }
 }
 
-state "AristaBootloaderHook" {
-  transition {
-    target = "AristaBootloaderExit"
-    trigger {
-      type = "immediate"
-    }
-  }
+# Runs at the Aboot prompt once the startup config is removed.
+hook "AristaBootloaderHook" {
+  continuation = "AristaBootloaderExit"
 }
 
 state "AristaBootloaderExit" {
@@ -123,6 +126,9 @@ state "AristaBootloaderExit" {
 }
 
 state "AristaWaitForReboot" {
+  description = "Rebooting into EOS"
+  phase       = "wipe"
+  progress    = 40
   transition {
     target = "AristaLoggingIn"
     trigger {
@@ -137,6 +143,9 @@ state "AristaWaitForReboot" {
 }
 
 state "AristaLoggingIn" {
+  description = "Logging in"
+  phase       = "verify"
+  progress    = 60
   transition {
     target = "AristaVersionOutput"
     trigger {
@@ -151,6 +160,9 @@ state "AristaLoggingIn" {
 }
 
 state "AristaVersionOutput" {
+  description = "Reading the version"
+  phase       = "verify"
+  progress    = 70
   transition {
     target = "AristaEnable"
     trigger {
@@ -158,8 +170,8 @@ state "AristaVersionOutput" {
       string = "localhost>"
     }
     action {
-      type = "Function"
-      func = "CaptureAristaVersion"
+      type  = "Capture"
+      regex = "(?:^Arista (?<Model>[a-zA-Z \\-0-9]+)$)|(?:^Serial number:\\s+(?<SerialNumber>[A-Za-z0-9]+)$)|(?:Software image version: (?<SoftwareVersion>[0-9\\.A-Za-z]+)$)"
     }
     action {
       type = "SendLine"
@@ -183,6 +195,9 @@ state "AristaEnable" {
 }
 
 state "AristaEraseCores" {
+  description = "Removing core dumps"
+  phase       = "wipe"
+  progress    = 80
   transition {
     target = "HookAristaCLI"
     trigger {
@@ -192,15 +207,11 @@ state "AristaEraseCores" {
   }
 }
 
-state "HookAristaCLI" {
-  transition {
-    target = "EndJob"
-    trigger {
-      type = "immediate"
-    }
-    action {
-      type = "SendLine"
-      line = "exit"
-    }
+# Runs at the EOS CLI once the switch is wiped and its information is recorded.
+hook "HookAristaCLI" {
+  continuation = "EndJob"
+  action {
+    type = "SendLine"
+    line = "exit"
   }
 }
@@ -4,43 +4,39 @@ depends = [
   "arista_wipe"
 ]
 
-state "AristaBootloaderHook" {
-  transition {
-    target = "AristaBootloaderHookExit"
-    trigger {
-      type = "immediate"
-    }
-    action {
-      type = "SendLine"
-      line = "echo DISABLE=True > /mnt/flash/zerotouch-config"
-    }
+handler "AristaBootloaderHook" {
+  entry    = "ProvisionAristaDisableZeroTouch"
+  priority = 100
+  action {
+    type = "SendLine"
+    line = "echo DISABLE=True > /mnt/flash/zerotouch-config"
   }
 }
 
-state "AristaBootloaderHookExit" {
+state "ProvisionAristaDisableZeroTouch" {
   transition {
-    target = "AristaBootloaderExit"
+    target = "return:AristaBootloaderHook"
     trigger {
-      type = "string"
+      type   = "string"
       string = "Aboot#"
     }
   }
 }
 
-state "HookAristaCLI" {
-  transition {
-    target = "ProvisionAristaEnterBash"
-    trigger {
-      type = "immediate"
-    }
-    action {
-      type = "SendLine"
-      line = ""
-    }
+handler "HookAristaCLI" {
+  entry    = "ProvisionAristaEnterBash"
+  priority = 100
+  terminal = true
+  action {
+    type = "SendLine"
+    line = ""
   }
 }
 
 state "ProvisionAristaEnterBash" {
+  description = "Provisioning"
+  phase       = "provision"
+  progress    = 85
   transition {
     target = "ProvisionAristaBash0"
     trigger {
@@ -94,17 +90,9 @@ echo "[BOOTSTRAP] Fetching provisioning script..."
 
 EOT
     }
-    action {
-      type = "Send"
-      text = "curl -o /mnt/flash/provision.sh \""
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "provision_url"
-    }
     action {
       type = "SendLine"
-      line = "/provision/arista/provision.sh\" || pvfail"
+      line = "curl -o /mnt/flash/provision.sh \"{{config.provision_url}}/provision/arista/provision.sh\" || pvfail"
     }
     action {
       type = "SendLine"
@@ -186,6 +174,9 @@ state "ProvisionAristaBash3" {
 }
 
 state "ProvisionAristaRunning" {
+  description = "Running the provisioning script"
+  phase       = "provision"
+  progress    = 90
   transition {
     target = "ProvisionAristaRunning"
     trigger {
@@ -298,6 +289,9 @@ state "ProvisionAristaFinish" {
 }
 
 state "ProvisionAristaRestart" {
+  description = "Restarting after provisioning"
+  phase       = "provision"
+  progress    = 95
   transition {
     target = "ProvisionAristaRestart"
     trigger {
@@ -16,6 +16,9 @@ state "SwitchDetect" {
 }
 
 state "ArubaWaitForBootloader" {
+  description = "Waiting for the bootloader"
+  phase       = "detect"
+  progress    = 5
   transition {
     target = "ArubaWaitForBootPrompt"
     trigger {
@@ -23,8 +26,8 @@ state "ArubaWaitForBootloader" {
       string = "Hit <Enter> to stop autoboot"
     }
     action {
-      type = "Function"
-      func = "CaptureArubaAPModel"
+      type  = "Capture"
+      regex = "^Model:\\s+(?<Model>[A-Za-z0-9-]+)$"
     }
     action {
       type = "SendLine"
@@ -37,6 +40,9 @@ state "ArubaWaitForBootloader" {
 }
 
 state "ArubaWaitForBootPrompt" {
+  description = "Stopping autoboot"
+  phase       = "wipe"
+  progress    = 20
   transition {
     target = "ArubaWaitForWipe"
     trigger {
@@ -51,6 +57,9 @@ state "ArubaWaitForBootPrompt" {
 }
 
 state "ArubaWaitForWipe" {
+  description = "Factory resetting"
+  phase       = "wipe"
+  progress    = 50
   transition {
     target = "ArubaWaitForSerial"
     trigger {
@@ -65,6 +74,9 @@ state "ArubaWaitForWipe" {
 }
 
 state "ArubaWaitForSerial" {
+  description = "Reading the serial number"
+  phase       = "verify"
+  progress    = 80
   transition {
     target = "EndJob"
     trigger {
@@ -72,8 +84,14 @@ state "ArubaWaitForSerial" {
       string = "apboot>"
     }
     action {
-      type = "Function"
-      func = "CaptureArubaAPSerial"
+      type  = "Capture"
+      regex = "^\\s+Serial\\s+:\\s+(?<SerialNumber>[A-Za-z0-9-]+)$"
+      first = true
+    }
+    action {
+      type  = "Capture"
+      regex = "^\\s+Wired MAC\\s+:\\s+(?<MacAddress>[A-Za-z0-9:]+)$"
+      first = true
     }
     action {
       type = "SendLine"
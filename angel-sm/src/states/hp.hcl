@@ -16,6 +16,9 @@ state "SwitchDetect" {
 }
 
 state "HPWaitForBootloader1" {
+  description = "Waiting for the boot menu"
+  phase       = "detect"
+  progress    = 5
   transition {
     target = "HPWaitForBootloader2"
     trigger {
@@ -45,8 +48,8 @@ state "HPWaitForBootloader2" {
       string = "Select profile"
     }
     action {
-      type = "Function"
-      func = "CaptureHPOSVersionBanner"
+      type  = "Capture"
+      regex = "1\\. Primary Software Image\\s*\\[(?<SoftwareVersion>[^\\s]+)\\]"
     }
     action {
       type = "Send"
@@ -59,6 +62,9 @@ state "HPWaitForBootloader2" {
 }
 
 state "HPEnterBootloader" {
+  description = "Entering the boot ROM console"
+  phase       = "wipe"
+  progress    = 20
   transition {
     target = "HPCaptureModel"
     trigger {
@@ -73,6 +79,9 @@ state "HPEnterBootloader" {
 }
 
 state "HPCaptureModel" {
+  description = "Reading the model and serial number"
+  phase       = "detect"
+  progress    = 30
   transition {
     target = "HPCaptureSerial"
     trigger {
@@ -80,8 +89,9 @@ state "HPCaptureModel" {
       string = "=>"
     }
     action {
-      type = "Function"
-      func = "CaptureHPSwitchModel"
+      type   = "Capture"
+      regex  = "(?:HP (?<Model>[^\\s]+) Switch|^\\s*ROM Version\\s*:\\s*(?<BootloaderVersion>[^\\s]+)|System Description\\s*:\\s*(?<model2>[^\\s]+)|(Serial Number\\s*:\\s*(?<SerialNumber>[^\\s]+)))"
+      groups = { model2 = "Model" }
     }
     action {
       type = "SendLine"
@@ -98,8 +108,8 @@ state "HPCaptureSerial" {
       string = "=>"
     }
     action {
-      type = "Function"
-      func = "CaptureHPSwitchSerial"
+      type  = "Capture"
+      regex = "serialNumber=(?<SerialNumber>[^\\s]+),"
     }
     action {
       type = "SendLine"
@@ -109,6 +119,9 @@ state "HPCaptureSerial" {
 }
 
 state "HPEraseConfirm" {
+  description = "Erasing all configuration"
+  phase       = "wipe"
+  progress    = 60
   transition {
     target = "HPWaitEraseComplete"
     trigger {
@@ -123,6 +136,9 @@ state "HPEraseConfirm" {
 }
 
 state "HPWaitEraseComplete" {
+  description = "Waiting for the erase to finish"
+  phase       = "wipe"
+  progress    = 80
   transition {
     target = "EndJob"
     trigger {
@@ -131,3 +147,41 @@ state "HPWaitEraseComplete" {
     }
   }
 }
+
+test "EraseAll" {
+  transcript = <<-EOT
+    ROM information:
+      Build directory: /sw/rom/build/bootromdl(t3g)
+      Build date: Oct 16 2014
+      Build version: KB.16.01.0006
+    Boot Profiles:
+
+    0. Monitor ROM Console
+    1. Primary Software Image   [KB.16.02.0012]
+    2. Secondary Software Image [KB.16.01.0006]
+
+    Select profile (primary): 0
+    => id
+    HP 5406zl Switch
+      ROM Version : KB.16.01.0006
+    => cat cfa0/idprofile01.inf
+    [Default]
+    serialNumber=SG12345678,
+    => erase-all
+    Continue (y/n)? y
+    Erasing flash...
+    Waiting for Speed Sense.
+    EOT
+  states = [
+    "Init", "SwitchDetect", "HPWaitForBootloader1", "HPWaitForBootloader2", "HPEnterBootloader",
+    "HPCaptureModel", "HPCaptureSerial", "HPEraseConfirm", "HPWaitEraseComplete", "EndJob", "JobFinished",
+  ]
+  sent = ["0id", "cat cfa0/idprofile01.inf", "erase-all", "y"]
+  information = [
+    { Vendor = "HP" },
+    { SoftwareVersion = "KB.16.02.0012" },
+    { Model = "5406zl" },
+    { BootloaderVersion = "KB.16.01.0006" },
+    { SerialNumber = "SG12345678" },
+  ]
+}
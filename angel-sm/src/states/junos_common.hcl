@@ -1,6 +1,20 @@
 id = "common_junos_wipe"
 
+routine "JunosShell" {
+  prompt {
+    type  = "regex"
+    regex = "root@(?:[A-Za-z0-9\\-]*:[A-Z]+:0%|:~)"
+  }
+}
+
 state "JunosLogin" {
+  description = "Logging in"
+  phase       = "wipe"
+  progress    = 40
+  # The login prompt may show up a few times while the switch boots.
+  limit {
+    max_visits = 10
+  }
   transition {
     target = "JunosLogin"
     trigger {
@@ -77,57 +91,21 @@ state "JunosLogin" {
 }
 
 state "JunosEnterHappyCli" {
-  transition {
-    target = "JunosEnterHappyCli2"
-    trigger {
-      type = "immediate"
-    }
-    action {
-      type = "SendLine"
-      line = "echo \"y\" | crontab -r"
-    }
-  }
-}
-
-state "JunosEnterHappyCli2" {
-  transition {
-    target = "JunosEnterHappyCli3"
-    trigger {
-      type  = "regex"
-      regex = "root@(?:[A-Za-z0-9\\-]*:[A-Z]+:0%|:~)"
-    }
-    action {
-      type = "SendLine"
-      line = "rm -rfv /var/tmp/autoreload* /tmp/provision* /tmp/autoreload* /var/core/core.* /var/log/* /var/tmp/*"
-    }
-  }
-}
-
-state "JunosEnterHappyCli3" {
-  transition {
-    target = "JunosEnterHappyCli4"
-    trigger {
-      type  = "regex"
-      regex = "root@(?:[A-Za-z0-9\\-]*:[A-Z]+:0%|:~)"
-    }
-    action {
-      type = "SendLine"
-      line = "sysctl hw.product.model ; sysctl hw.chassis.serialid"
-    }
-  }
-}
-
-state "JunosEnterHappyCli4" {
-  transition {
-    target = "JunosEnterHappyCli5"
-    trigger {
-      type  = "regex"
-      regex = "root@(?:[A-Za-z0-9\\-]*:[A-Z]+:0%|:~)"
-    }
-    action {
-      type = "SendLine"
-      line = "nand-mediack"
-    }
+  description = "Cleaning up files"
+  phase       = "wipe"
+  progress    = 50
+  on_enter {
+    type = "SendLine"
+    line = "echo \"y\" | crontab -r"
+  }
+  call {
+    routine  = "JunosShell"
+    commands = [
+      "rm -rfv /var/tmp/autoreload* /tmp/provision* /tmp/autoreload* /var/core/core.* /var/log/* /var/tmp/*",
+      "sysctl hw.product.model ; sysctl hw.chassis.serialid",
+      "nand-mediack",
+    ]
+    return = "JunosEnterHappyCli5"
   }
 }
 
@@ -157,6 +135,9 @@ state "JunosEnterHappyCli5" {
 }
 
 state "JunosHappyCli" {
+  description = "Starting the CLI"
+  phase       = "verify"
+  progress    = 60
   transition {
     target = "JunosHappyCli"
     trigger {
@@ -174,10 +155,6 @@ state "JunosHappyCli" {
       type   = "string"
       string = "root>"
     }
-    action {
-      type = "SendLine"
-      line = "show version | no-more"
-    }
   }
   transition {
     target = "JunosVersionOutput"
@@ -189,10 +166,6 @@ state "JunosHappyCli" {
       type = "AddDeviceInfo"
       flag = "KeptHostname"
     }
-    action {
-      type = "SendLine"
-      line = "show version | no-more"
-    }
   }
 }
 
@@ -203,14 +176,6 @@ state "JunosBackupImageCli1" {
       type   = "string"
       string = "root>"
     }
-    action {
-      type = "AddDeviceInfo"
-      flag = "AlternateImage"
-    }
-    action {
-      type = "SendLine"
-      line = "request system snapshot media internal slice alternate"
-    }
   }
   transition {
     target = "JunosBackupImageCli2"
@@ -222,18 +187,21 @@ state "JunosBackupImageCli1" {
       type = "AddDeviceInfo"
       flag = "KeptHostname"
     }
-    action {
-      type = "AddDeviceInfo"
-      flag = "AlternateImage"
-    }
-    action {
-      type = "SendLine"
-      line = "request system snapshot media internal slice alternate"
-    }
   }
 }
 
 state "JunosBackupImageCli2" {
+  description = "Copying the image to the backup slice"
+  phase       = "wipe"
+  progress    = 55
+  on_enter {
+    type = "AddDeviceInfo"
+    flag = "AlternateImage"
+  }
+  on_enter {
+    type = "SendLine"
+    line = "request system snapshot media internal slice alternate"
+  }
   transition {
     target = "JunosBackupImageCli3"
     trigger {
@@ -297,6 +265,13 @@ state "JunosAwaitBackupImageCliRecoveryFinish" {
 }
 
 state "JunosVersionOutput" {
+  description = "Reading the version"
+  phase       = "verify"
+  progress    = 65
+  on_enter {
+    type = "SendLine"
+    line = "show version | no-more"
+  }
   transition {
     target = "JunosChassisOutput"
     trigger {
@@ -304,8 +279,9 @@ state "JunosVersionOutput" {
       regex = "root(@[A-Za-z0-9\\-]+)?>"
     }
     action {
-      type = "Function"
-      func = "CaptureJunosVersion"
+      type     = "ParseTable"
+      template = "juniper_junos_show_version"
+      columns  = { Model = "Model", Version = "SoftwareVersion" }
     }
     action {
       type = "SendLine"
@@ -315,6 +291,9 @@ state "JunosVersionOutput" {
 }
 
 state "JunosChassisOutput" {
+  description = "Reading the serial number"
+  phase       = "verify"
+  progress    = 70
   transition {
     target = "HookJunosCLI"
     trigger {
@@ -323,26 +302,32 @@ state "JunosChassisOutput" {
     }
     # TODO: Maybe switch to sysctl hw.product.model ; sysctl hw.chassis.serialid
     action {
-      type = "Function"
-      func = "CaptureChassisOutput"
+      type     = "ParseTable"
+      template = "juniper_junos_show_chassis_hardware"
+      columns  = { Serial = "SerialNumber" }
+      filter   = { Item = "^Chassis$" }
+    }
+    action {
+      type      = "ParseTable"
+      template  = "juniper_junos_show_chassis_hardware"
+      inventory = true
     }
   }
 }
 
-state "HookJunosCLI" {
-  transition {
-    target = "JunosPoweroff"
-    trigger {
-      type = "immediate"
-    }
-    action {
-      type = "SendLine"
-      line = ""
-    }
+# Runs at the Junos CLI once the switch is wiped and its information is recorded.
+hook "HookJunosCLI" {
+  continuation = "JunosPoweroff"
+  action {
+    type = "SendLine"
+    line = ""
   }
 }
 
 state "JunosPoweroff" {
+  description = "Powering off"
+  phase       = "poweroff"
+  progress    = 85
   transition {
     target = "JunosPoweroff2"
     trigger {
@@ -357,16 +342,10 @@ state "JunosPoweroff" {
 }
 
 state "JunosPoweroff2" {
-  transition {
-    target = "JunosPoweroff3"
-    trigger {
-      type  = "regex"
-      regex = "root@(?:[A-Za-z0-9\\-]*:[A-Z]+:0%|:~)"
-    }
-    action {
-      type = "SendLine"
-      line = "sysctl hw.re.vm_mode"
-    }
+  call {
+    routine  = "JunosShell"
+    commands = ["sysctl hw.re.vm_mode"]
+    return   = "JunosPoweroff3"
   }
 }
 
@@ -474,6 +453,9 @@ state "JunosPoweroffVMHostConfirm" {
 }
 
 state "JunosWaitForPoweroff" {
+  description = "Waiting for the switch to power off"
+  phase       = "poweroff"
+  progress    = 95
   transition {
     target = "EndJob"
     trigger {
@@ -502,4 +484,15 @@ state "JunosWaitForPoweroff" {
       string = "reboot: Power down"
     }
   }
+  transition {
+    target = "EndJob"
+    trigger {
+      type     = "timeout"
+      duration = 600.0
+    }
+    action {
+      type = "AddDeviceInfo"
+      flag = "TimedOut"
+    }
+  }
 }
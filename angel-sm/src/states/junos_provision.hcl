@@ -4,20 +4,27 @@ depends = [
   "common_junos_wipe",
 ]
 
-state "HookJunosCLI" {
-  transition {
-    target = "ProvisionJunos1"
-    trigger {
-      type = "immediate"
-    }
-    action {
-      type = "SendLine"
-      line = ""
-    }
+routine "JunosConfigure" {
+  prompt {
+    type  = "regex"
+    regex = "root(@[A-Za-z0-9\\-]+)?#"
+  }
+}
+
+handler "HookJunosCLI" {
+  entry    = "ProvisionJunos1"
+  priority = 100
+  terminal = true
+  action {
+    type = "SendLine"
+    line = ""
   }
 }
 
 state "ProvisionJunos1" {
+  description = "Provisioning"
+  phase       = "provision"
+  progress    = 75
   transition {
     target = "ProvisionJunos2"
     trigger {
@@ -54,7 +61,7 @@ state "ProvisionJunos3" {
     }
     action {
       type = "SendLine"
-      line = "Cyberwurst"
+      line = "{{secret.root_password}}"
     }
   }
 }
@@ -68,64 +75,21 @@ state "ProvisionJunos4" {
     }
     action {
       type = "SendLine"
-      line = "Cyberwurst"
+      line = "{{secret.root_password}}"
     }
   }
 }
 
 state "ProvisionJunos5" {
-  transition {
-    target = "ProvisionJunos51"
-    trigger {
-      type  = "regex"
-      regex = "root(@[A-Za-z0-9\\-]+)?#"
-    }
-    action {
-      type = "SendLine"
-      line = "set interfaces vme unit 0 family inet dhcp"
-    }
-  }
-}
-
-state "ProvisionJunos51" {
-  transition {
-    target = "ProvisionJunos6"
-    trigger {
-      type  = "regex"
-      regex = "root(@[A-Za-z0-9\\-]+)?#"
-    }
-    action {
-      type = "SendLine"
-      line = "set interfaces me0 unit 0 family inet dhcp"
-    }
-  }
-}
-
-state "ProvisionJunos6" {
-  transition {
-    target = "ProvisionJunos7"
-    trigger {
-      type  = "regex"
-      regex = "root(@[A-Za-z0-9\\-]+)?#"
-    }
-    action {
-      type = "SendLine"
-      line = "delete chassis auto-image-upgrade"
-    }
-  }
-}
-
-state "ProvisionJunos7" {
-  transition {
-    target = "ProvisionJunos71"
-    trigger {
-      type  = "regex"
-      regex = "root(@[A-Za-z0-9\\-]+)?#"
-    }
-    action {
-      type = "SendLine"
-      line = "commit"
-    }
+  call {
+    routine  = "JunosConfigure"
+    commands = [
+      "set interfaces vme unit 0 family inet dhcp",
+      "set interfaces me0 unit 0 family inet dhcp",
+      "delete chassis auto-image-upgrade",
+      "commit",
+    ]
+    return = "ProvisionJunos71"
   }
 }
 
@@ -176,17 +140,9 @@ state "ProvisionJunos9" {
       type  = "regex"
       regex = "root@([A-Za-z0-9\\-]+)?:(RE|LC):0%"
     }
-    action {
-      type = "Send"
-      text = "set REALTTY=`tty`; set PING_TGT=\""
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "provision_ping_target"
-    }
     action {
       type = "SendLine"
-      line = "\""
+      line = "set REALTTY=`tty`; set PING_TGT=\"{{config.provision_ping_target}}\""
     }
   }
 }
@@ -206,17 +162,9 @@ state "ProvisionJunos10" {
       type = "SendLine"
       line = "export REALTTY=`tty`"
     }
-    action {
-      type = "Send"
-      text = "export PING_TGT=\""
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "provision_ping_target"
-    }
     action {
       type = "SendLine"
-      line = "\""
+      line = "export PING_TGT=\"{{config.provision_ping_target}}\""
     }
     action {
       type = "SendLine"
@@ -237,17 +185,9 @@ echo "[BOOTSTRAP] Fetching provisioning script..."
 
 EOT
     }
-    action {
-      type = "Send"
-      text = "fetch -o /tmp/provision.sh \""
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "provision_url"
-    }
     action {
       type = "SendLine"
-      line = "/provision/juniper/provision.sh\" || pvfail"
+      line = "fetch -o /tmp/provision.sh \"{{config.provision_url}}/provision/juniper/provision.sh\" || pvfail"
     }
     action {
       type = "SendLine"
@@ -282,6 +222,9 @@ state "ProvisionJunos11" {
 }
 
 state "ProvisionJunosRunning" {
+  description = "Running the provisioning script"
+  phase       = "provision"
+  progress    = 80
   transition {
     target = "ProvisionJunosRunning"
     trigger {
@@ -422,6 +365,9 @@ state "ProvisionJunosFinish" {
 }
 
 state "ProvisionJunosWaitReboot" {
+  description = "Rebooting after provisioning"
+  phase       = "provision"
+  progress    = 90
   transition {
     target = "ProvisionJunosWaitReboot"
     trigger {
@@ -488,7 +434,7 @@ state "ProvisionJunosRebootLogin" {
     }
     action {
       type = "SendLine"
-      line = "Cyberwurst"
+      line = "{{secret.root_password}}"
     }
   }
 }
@@ -4,39 +4,34 @@ depends = [
   "common_junos_wipe"
 ]
 
+# EX3300s are known for their broken flash chips
+watcher "SCSIErrors" {
+  trigger {
+    type   = "string"
+    string = "SCSI Status"
+  }
+  flag = "SCSIErrors"
+}
+
 state "SwitchDetect" {
   merge = "append"
   transition {
-    target = "LegacyJunosUBoot1"
-    trigger {
-      type   = "string"
-      string = "U-Boot 1.1"
-    }
-    action {
-      type   = "AddDeviceInfo"
-      Vendor = "Juniper"
-    }
-  }
-  transition {
-    target = "LegacyJunosUBoot1"
+    target = "LegacyJunosUBoot2"
     trigger {
-      type   = "string"
-      string = "U-Boot 2010.03"
+      type = "sequence"
+      trigger {
+        type  = "regex"
+        regex = "U-Boot (1\\.1|2010\\.03)"
+      }
+      trigger {
+        type   = "string"
+        string = "scanning bus for devices"
+      }
     }
     action {
       type   = "AddDeviceInfo"
       Vendor = "Juniper"
     }
-  }
-}
-
-state "LegacyJunosUBoot1" {
-  transition {
-    target = "LegacyJunosUBoot2"
-    trigger {
-      type   = "string"
-      string = "scanning bus for devices"
-    }
     action {
       type = "SendControl"
       char = "c"
@@ -45,6 +40,9 @@ state "LegacyJunosUBoot1" {
 }
 
 state "LegacyJunosUBoot2" {
+  description = "Interrupting U-Boot"
+  phase       = "detect"
+  progress    = 10
   transition {
     target = "LegacyJunosUBoot3"
     trigger {
@@ -87,6 +85,9 @@ state "LegacyJunosUBoot4" {
 }
 
 state "LegacyJunosLoader1" {
+  description = "Booting into single user mode"
+  phase       = "wipe"
+  progress    = 15
   transition {
     target = "LegacyJunosLoader2"
     trigger {
@@ -128,6 +129,9 @@ state "LegacyJunosLoader2" {
 }
 
 state "LegacyJunosWaitForRecoveryPrompt" {
+  description = "Waiting for the recovery prompt"
+  phase       = "wipe"
+  progress    = 20
   transition {
     target = "LegacyJunosAwaitRecoveryShell"
     trigger {
@@ -139,29 +143,28 @@ state "LegacyJunosWaitForRecoveryPrompt" {
       line = "recovery"
     }
   }
-  # EX3300s are known for their broken flash chips
-  transition {
-    target = "LegacyJunosWaitForRecoveryPrompt"
-    trigger {
-      type   = "string"
-      string = "SCSI Status"
-    }
-    action {
-      type = "AddDeviceInfo"
-      flag = "SCSIErrors"
-    }
-  }
   # Boot loops have happened before.
   transition {
-    target = "LegacyJunosUBoot1"
+    target = "LegacyJunosUBoot2"
     trigger {
-      type   = "string"
-      string = "U-Boot 1.1"
+      type = "sequence"
+      trigger {
+        type   = "string"
+        string = "U-Boot 1.1"
+      }
+      trigger {
+        type   = "string"
+        string = "scanning bus for devices"
+      }
     }
     action {
       type = "AddDeviceInfo"
       flag = "BootLoop"
     }
+    action {
+      type = "SendControl"
+      char = "c"
+    }
   }
 }
 
@@ -181,33 +184,18 @@ state "LegacyJunosAwaitRecoveryShell" {
       line = "request system zeroize"
     }
   }
-  # EX3300s are known for their broken flash chips
-  transition {
-    target = "LegacyJunosAwaitRecoveryShell"
-    trigger {
-      type   = "string"
-      string = "SCSI Status"
-    }
-    action {
-      type = "AddDeviceInfo"
-      flag = "SCSIErrors"
-    }
-  }
 
-  # Junipers like to have fsck issues if you unplug them wrong.
+  # Junipers like to have fsck issues if you unplug them wrong. The script reboots the switch,
+  # so wait for U-Boot again like on the first boot.
   transition {
-    target = "LegacyJunosUBoot1"
+    target = "SwitchDetect"
     trigger {
       type   = "string"
       string = "error: filesystem consistency checks (fsck -p -y) failed"
     }
     action {
-      type = "SendLine"
-      line = "shell"
-    }
-    action {
-      type = "Function"
-      func = "FixFS"
+      type   = "Script"
+      script = "fix_fs"
     }
     action {
       type     = "Delay"
@@ -249,6 +237,9 @@ state "LegacyJunosAwaitRecoveryShell" {
 }
 
 state "LegacyJunosAnswerZeroize" {
+  description = "Zeroizing"
+  phase       = "wipe"
+  progress    = 30
   transition {
     target = "LegacyJunosAwaitZeroizeFinish"
     trigger {
@@ -263,6 +254,9 @@ state "LegacyJunosAnswerZeroize" {
 }
 
 state "LegacyJunosAwaitZeroizeFinish" {
+  description = "Waiting for the zeroize to finish"
+  phase       = "wipe"
+  progress    = 35
   transition {
     target = "JunosLogin"
     trigger {
@@ -297,3 +291,23 @@ state "LegacyJunosAwaitZeroizeFinish" {
     }
   }
 }
+
+test "InterruptUBoot" {
+  transcript = <<-EOT
+    U-Boot 2010.03 (Jun 19 2012 - 09:53:44)
+
+    DRAM:  512 MB
+    scanning bus for devices... 1 USB Device(s) found
+    => printenv
+    bootcmd=run boot_unattended
+    => setenv boot_unattended
+    => boot
+    EOT
+  states = [
+    "Init", "SwitchDetect", "LegacyJunosUBoot2", "LegacyJunosUBoot3", "LegacyJunosUBoot4",
+    "LegacyJunosLoader1",
+  ]
+  information = [
+    { Vendor = "Juniper" },
+  ]
+}
@@ -82,6 +82,9 @@ state "SwitchDetect" {
 }
 
 state "ModernJunosWaitForBootloader" {
+  description = "Waiting for the bootloader"
+  phase       = "detect"
+  progress    = 5
   transition {
     target = "ModernJunosEvo1"
     trigger {
@@ -166,6 +169,9 @@ state "ModernJunosQFXBypass" {
   }
 
 state "ModernJunosEvo1" {
+  description = "Setting a temporary root password"
+  phase       = "wipe"
+  progress    = 15
   transition {
     target = "ModernJunosEvo2"
     trigger {
@@ -255,6 +261,9 @@ state "ModernJunosEvo4" {
 }
 
 state "ModernJunosQFX1" {
+  description = "Selecting the recovery image"
+  phase       = "wipe"
+  progress    = 15
   transition {
     target = "ModernJunosQFX2"
     trigger {
@@ -305,6 +314,9 @@ state "ModernJunosQFX2" {
 }
 
 state "ModernJunosBootloader1" {
+  description = "Entering recovery mode"
+  phase       = "wipe"
+  progress    = 15
   transition {
     target = "ModernJunosBootloader2"
     trigger {
@@ -364,6 +376,9 @@ state "ModernJunosAwaitRecoveryShell" {
 }
 
 state "ModernJunosAnswerZeroize" {
+  description = "Zeroizing"
+  phase       = "wipe"
+  progress    = 25
   transition {
     target = "ModernJunosAwaitZeroizeFinish"
     trigger {
@@ -393,6 +408,9 @@ state "ModernJunosAnswerZeroize" {
 }
 
 state "ModernJunosAwaitZeroizeFinish" {
+  description = "Waiting for the zeroize to finish"
+  phase       = "wipe"
+  progress    = 30
   transition {
     target = "ModernJunosAwaitZeroizeFinish2"
     trigger {
@@ -422,3 +440,93 @@ state "ModernJunosAwaitZeroizeFinish2" {
     }
   }
 }
+
+test "RecoveryZeroize" {
+  transcript = <<-EOT
+    U-Boot 2016.01 (Jan 01 2019 - 12:00:00 +0000)
+    Booting from Flash A
+    Hit [Enter] to boot immediately, or space bar for command prompt.
+    Booting [/packages/sets/active/boot/os/kernel] in 9 seconds... (press Ctrl-C to interrupt)
+
+    Main Menu
+
+    1. Boot [J]unos volume
+    2. Boot Junos volume in [S]afe mode
+    3. [R]eboot
+    4. [B]oot menu
+    5. [M]ore options
+
+    Choice: 5
+
+    Options Menu
+
+    1. Recover [J]unos volume
+    2. Recovery mode - [C]LI
+    3. Check [F]ile system
+    4. Enable [V]erbose boot
+    5. [B]oot prompt
+    6. [M]ain menu
+
+    Choice: 2
+    Recovery CLI
+
+    root> request system zeroize
+    warning: System will be rebooted and may not boot without configuration
+    Erase all data, including configuration and log files? [yes,no] (no) yes
+
+    warning: zeroizing fpc0
+    mgd: warning: activating factory configuration
+
+    Amnesiac (ttyu0)
+
+    login: root
+
+    --- JUNOS 18.4R2-S3 Kernel 64-bit  JNPR-11.0-20190617.cd2ef5c_buil
+    root@:RE:0% echo "y" | crontab -r
+    root@:RE:0% rm -rfv /var/tmp/autoreload* /tmp/provision* /tmp/autoreload* /var/core/core.* /var/log/* /var/tmp/*
+    root@:RE:0% sysctl hw.product.model ; sysctl hw.chassis.serialid
+    hw.product.model: ex2300-c-12p
+    hw.chassis.serialid: JW3619AV0123
+    root@:RE:0% nand-mediack
+    Media check on da0 on ex platforms
+    root@:RE:0% sleep 30; cli
+    root> show version | no-more
+    fpc0:
+    --------------------------------------------------------------------------
+    Model: ex2300-c-12p
+    Junos: 18.4R2-S3
+
+    root> show chassis hardware | no-more
+    Hardware inventory:
+    Item             Version  Part number  Serial number     Description
+    Chassis                                JW3619AV0123      EX2300-C-12P
+
+    root>
+    root> start shell
+    root@:RE:0% sysctl hw.re.vm_mode
+    sysctl: unknown oid 'hw.re.vm_mode'
+    root@:RE:0% exit
+    exit
+
+    root> request system power-off at now
+    Power Off the system at now? [yes,no] (no) yes
+
+    Powering system off
+    EOT
+  states = [
+    "Init", "SwitchDetect", "ModernJunosWaitForBootloader", "ModernJunosBootloader1",
+    "ModernJunosBootloader2", "ModernJunosAwaitRecoveryShell", "ModernJunosAnswerZeroize",
+    "ModernJunosAwaitZeroizeFinish", "ModernJunosAwaitZeroizeFinish2", "JunosLogin",
+    "JunosEnterHappyCli", "JunosEnterHappyCli_JunosShell1", "JunosEnterHappyCli_JunosShell2",
+    "JunosEnterHappyCli5", "JunosHappyCli", "JunosVersionOutput", "JunosChassisOutput", "HookJunosCLI",
+    "JunosPoweroff", "JunosPoweroff2", "JunosPoweroff3", "JunosPoweroffClassic",
+    "JunosPoweroffClassicConfirm", "JunosWaitForPoweroff", "EndJob", "JobFinished",
+  ]
+  information = [
+    { Vendor = "Juniper" },
+    { Model = "ex2300-c-12p" },
+    { SoftwareVersion = "18.4R2-S3" },
+    { SerialNumber = "JW3619AV0123" },
+    { InventoryItem = { Item = "Chassis", Serial = "JW3619AV0123", Description = "EX2300-C-12P" } },
+  ]
+}
@@ -4,36 +4,22 @@ id = "recover"
 state "SwitchDetect" {
   merge = "append"
   transition {
-    target = "LegacyJunosUBoot1"
-    trigger {
-      type   = "string"
-      string = "U-Boot 1.1"
-    }
-    action {
-      type   = "AddDeviceInfo"
-      Vendor = "Juniper"
-    }
-  }
-  transition {
-    target = "LegacyJunosUBoot1"
+    target = "LegacyJunosUBoot2"
     trigger {
-      type   = "string"
-      string = "U-Boot 2010.03"
+      type = "sequence"
+      trigger {
+        type  = "regex"
+        regex = "U-Boot (1\\.1|2010\\.03)"
+      }
+      trigger {
+        type   = "string"
+        string = "scanning bus for devices"
+      }
     }
     action {
       type   = "AddDeviceInfo"
       Vendor = "Juniper"
     }
-  }
-}
-
-state "LegacyJunosUBoot1" {
-  transition {
-    target = "LegacyJunosUBoot2"
-    trigger {
-      type   = "string"
-      string = "scanning bus for devices"
-    }
     action {
       type = "SendControl"
       char = "c"
@@ -42,11 +28,15 @@ state "LegacyJunosUBoot1" {
 }
 
 state "LegacyJunosUBoot2" {
+  description = "Interrupting U-Boot"
+  phase       = "detect"
+  progress    = 10
   transition {
     target = "LegacyJunosUBoot3"
     trigger {
-      type   = "string"
-      string = "=>"
+      type        = "string"
+      string      = "=>"
+      anchor      = "line_start"
     }
     action {
       type = "SendLine"
@@ -59,8 +49,10 @@ state "LegacyJunosUBoot3" {
   transition {
     target = "LegacyJunosUBoot4"
     trigger {
-      type   = "string"
-      string = "=>"
+      type        = "string"
+      string      = "=>"
+      anchor      = "line_start"
+      ignore_echo = true
     }
     action {
       type = "SendLine"
@@ -73,8 +65,10 @@ state "LegacyJunosUBoot4" {
   transition {
     target = "LegacyJunosLoader1"
     trigger {
-      type   = "string"
-      string = "=>"
+      type        = "string"
+      string      = "=>"
+      anchor      = "line_start"
+      ignore_echo = true
     }
     action {
       type = "SendLine"
@@ -84,6 +78,9 @@ state "LegacyJunosUBoot4" {
 }
 
 state "LegacyJunosLoader1" {
+  description = "Reinstalling Junos"
+  phase       = "wipe"
+  progress    = 20
   transition {
     target = "EndJob"
     trigger {
@@ -138,17 +135,9 @@ state "LegacyJunosLoader3" {
       type   = "string"
       string = "loader>"
     }
-    action {
-      type = "Send"
-      text = "set ipaddr="
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "tftp_device_ip"
-    }
     action {
       type = "SendLine"
-      line = ""
+      line = "set ipaddr={{config.tftp_device_ip}}"
     }
   }
 }
@@ -174,17 +163,9 @@ state "LegacyJunosLoader5" {
       type   = "string"
       string = "loader>"
     }
-    action {
-      type = "Send"
-      text = "set gatewayip="
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "tftp_server_ip"
-    }
     action {
       type = "SendLine"
-      line = ""
+      line = "set gatewayip={{config.tftp_server_ip}}"
     }
   }
 }
@@ -197,17 +178,9 @@ state "LegacyJunosLoader6" {
       type   = "string"
       string = "loader>"
     }
-    action {
-      type = "Send"
-      text = "set serverip="
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "tftp_server_ip"
-    }
     action {
       type = "SendLine"
-      line = ""
+      line = "set serverip={{config.tftp_server_ip}}"
     }
   }
 }
@@ -219,30 +192,17 @@ state "LegacyJunosLoader7" {
       type   = "string"
       string = "loader>"
     }
-    action {
-      type = "Send"
-      text = "install --format tftp://"
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "tftp_server_ip"
-    }
-    action {
-      type = "Send"
-      text = "/"
-    }
-    action {
-      type = "SendConfigValue"
-      key  = "tftp_server_file"
-    }
     action {
       type = "SendLine"
-      line = ""
+      line = "install --format tftp://{{config.tftp_server_ip}}/{{config.tftp_server_file}}"
     }
   }
 }
 
 state "WaitReimageFinish" {
+  description = "Waiting for the reinstall to finish"
+  phase       = "wipe"
+  progress    = 50
   transition {
     target = "JunosLogin"
     trigger {
@@ -297,6 +257,9 @@ state "JunosHappyCli" {
 
 
 state "JunosPoweroffConfirm" {
+  description = "Powering off"
+  phase       = "poweroff"
+  progress    = 90
   transition {
     target = "JunosWaitForPoweroff"
     trigger {
@@ -25,6 +25,9 @@ state "SwitchDetect" {
 }
 
 state "VMHostJunosLoader1" {
+  description = "Waiting for the boot menu"
+  phase       = "detect"
+  progress    = 5
   transition {
     target = "VMHostJunosLoader2"
     trigger {
@@ -57,6 +60,9 @@ state "VMHostJunosLoader2" {
 }
 
 state "VMHostJunosSelect1" {
+  description = "Selecting the recovery image"
+  phase       = "wipe"
+  progress    = 15
   transition {
     target = "VMHostJunosSelect2"
     trigger {
@@ -113,6 +119,9 @@ state "VMHostJunosSelect4" {
 }
 
 state "VMHostJunosWaitFormat" {
+  description = "Reinstalling from the recovery image"
+  phase       = "wipe"
+  progress    = 25
   transition {
     target = "VMHostJunosWaitBoot"
     trigger {
@@ -123,6 +132,9 @@ state "VMHostJunosWaitFormat" {
 }
 
 state "VMHostJunosWaitBoot" {
+  description = "Waiting for Junos to boot"
+  phase       = "wipe"
+  progress    = 35
   transition {
     target = "JunosLogin"
     trigger {
@@ -0,0 +1,246 @@
+use crate::AngelJob;
+use color_eyre::eyre::eyre;
+use cthulhu_common::devinfo::DeviceInformationKind;
+use std::fmt::{Display, Formatter};
+
+/// A variable referenced from a template, written as `{{namespace.name}}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplateVariable {
+    /// `{{config.key}}`: a value from the job config.
+    Config(String),
+    /// `{{info.Kind}}`: device information captured earlier in the job.
+    Info(DeviceInformationKind),
+    /// `{{secret.name}}`: a secret from the angel config.
+    Secret(String),
+    /// `{{var.name}}`: a variable set by an earlier action.
+    Variable(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplateSegment<'a> {
+    Literal(&'a str),
+    Variable(TemplateVariable),
+}
+
+fn parse_variable(v: &str) -> color_eyre::Result<TemplateVariable> {
+    let (namespace, name) = v
+        .trim()
+        .split_once('.')
+        .ok_or_else(|| eyre!("template variable {v:?} is missing a namespace"))?;
+    match namespace {
+        "config" => Ok(TemplateVariable::Config(name.to_string())),
+        "info" => Ok(TemplateVariable::Info(name.parse().map_err(|e| eyre!("{e}"))?)),
+        "secret" => Ok(TemplateVariable::Secret(name.to_string())),
+        "var" => Ok(TemplateVariable::Variable(name.to_string())),
+        _ => Err(eyre!("unknown template namespace {namespace:?} in {v:?}")),
+    }
+}
+
+/// Split a template into literal text and variables.
+pub fn parse_template(template: &str) -> color_eyre::Result<Vec<TemplateSegment<'_>>> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            segments.push(TemplateSegment::Literal(&rest[..start]));
+        }
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| eyre!("unterminated template variable in {template:?}"))?;
+        segments.push(TemplateSegment::Variable(parse_variable(
+            &rest[start + 2..start + end],
+        )?));
+        rest = &rest[start + end + 2..];
+    }
+    if !rest.is_empty() {
+        segments.push(TemplateSegment::Literal(rest));
+    }
+    Ok(segments)
+}
+
+/// A template could not be filled in. The runner ends the job when it sees this error.
+#[derive(Debug)]
+pub struct TemplateError(pub String);
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unable to fill in template: {}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Fill in all variables of a template. Missing variables are a [`TemplateError`].
+pub async fn render_template<T: AngelJob>(job: &T, template: &str) -> color_eyre::Result<String> {
+    if !template.contains("{{") {
+        return Ok(template.to_string());
+    }
+    fill_template(job, template)
+        .await
+        .map_err(|e| TemplateError(format!("{e}")).into())
+}
+
+async fn fill_template<T: AngelJob>(job: &T, template: &str) -> color_eyre::Result<String> {
+    let mut out = String::new();
+    for segment in parse_template(template)? {
+        match segment {
+            TemplateSegment::Literal(s) => out.push_str(s),
+            TemplateSegment::Variable(TemplateVariable::Config(key)) => {
+                let v = job
+                    .get_job_config_key(&key)
+                    .await
+                    .ok_or_else(|| eyre!("no such config item: {key}"))?;
+                out.push_str(&v);
+            }
+            TemplateSegment::Variable(TemplateVariable::Info(kind)) => {
+                let info = job.get_information().await;
+                let v = info
+                    .iter()
+                    .filter_map(|i| i.value())
+                    .find(|(k, _)| *k == kind)
+                    .map(|(_, v)| v)
+                    .ok_or_else(|| eyre!("no device information of kind {kind:?} recorded"))?;
+                out.push_str(v);
+            }
+            TemplateSegment::Variable(TemplateVariable::Secret(name)) => {
+                let v = job
+                    .get_secret(&name)
+                    .await
+                    .ok_or_else(|| eyre!("no such secret: {name}"))?;
+                out.push_str(&v);
+            }
+            TemplateSegment::Variable(TemplateVariable::Variable(name)) => {
+                let v = job
+                    .get_variables()
+                    .await
+                    .remove(&name)
+                    .ok_or_else(|| eyre!("no such variable: {name}"))?;
+                out.push_str(&v);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Whether a template references a secret.
+pub fn uses_secret(template: &str) -> color_eyre::Result<bool> {
+    Ok(parse_template(template)?
+        .iter()
+        .any(|s| matches!(s, TemplateSegment::Variable(TemplateVariable::Secret(_)))))
+}
+
+/// Fill in a template whose result is logged and published, like a job variable.
+/// Secrets are refused, as they would end up in plain text outside the angel.
+pub async fn render_public_template<T: AngelJob>(job: &T, template: &str) -> color_eyre::Result<String> {
+    if uses_secret(template).unwrap_or(false) {
+        return Err(TemplateError(format!("secrets can not be used in {template:?}, it is logged and published")).into());
+    }
+    render_template(job, template).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StateMachineBuilder;
+    use crate::replay::replay_log;
+    use cthulhu_common::devinfo::DeviceInformation;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn parse() -> color_eyre::Result<()> {
+        assert_eq!(
+            parse_template("fetch {{ config.provision_url }}/provision.sh {{info.SerialNumber}}")?,
+            vec![
+                TemplateSegment::Literal("fetch "),
+                TemplateSegment::Variable(TemplateVariable::Config("provision_url".to_string())),
+                TemplateSegment::Literal("/provision.sh "),
+                TemplateSegment::Variable(TemplateVariable::Info(DeviceInformationKind::SerialNumber)),
+            ]
+        );
+        assert_eq!(parse_template("pvfail() { exit 1 }")?.len(), 1);
+        assert!(parse_template("{{config.unterminated").is_err());
+        assert!(parse_template("{{nonsense}}").is_err());
+        assert!(parse_template("{{info.Colour}}").is_err());
+        assert!(uses_secret("root {{ secret.root_password }}")?);
+        assert!(!uses_secret("{{var.secret}}")?);
+        Ok(())
+    }
+
+    const INFO_STATES: &str = r#"
+id = "info_test"
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "EndJob"
+    trigger {
+      type   = "string"
+      string = "start"
+    }
+    action {
+      type   = "AddDeviceInfo"
+      Vendor = "Old"
+    }
+    action {
+      type   = "AddDeviceInfo"
+      Vendor = "New"
+    }
+    action {
+      type = "SendLine"
+      line = "vendor {{info.Vendor}}"
+    }
+  }
+}
+"#;
+
+    #[tokio::test]
+    async fn latest_information() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_state_file(hcl::from_str(INFO_STATES)?);
+        builder.activate_state_file("info_test")?;
+        let sm = builder.build()?;
+
+        let report = replay_log(Arc::new(sm), b"start", BTreeMap::new()).await?;
+
+        assert!(report.finished(), "{report:?}");
+        assert_eq!(report.information, vec![DeviceInformation::Vendor("New".to_string())]);
+        assert_eq!(report.sent, vec!["vendor New"]);
+        Ok(())
+    }
+
+    const MISSING_CONFIG_STATES: &str = r#"
+id = "missing_config_test"
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "EndJob"
+    trigger {
+      type   = "string"
+      string = "start"
+    }
+    action {
+      type = "SendLine"
+      line = "fetch {{config.provision_url}}"
+    }
+  }
+}
+"#;
+
+    #[tokio::test]
+    async fn missing_config_ends_job() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_state_file(hcl::from_str(MISSING_CONFIG_STATES)?);
+        builder.activate_state_file("missing_config_test")?;
+        let sm = builder.build()?;
+
+        let report = replay_log(Arc::new(sm), b"start", BTreeMap::new()).await?;
+
+        assert!(report.finished(), "{report:?}");
+        assert_eq!(report.states, vec!["Init", "SwitchDetect", "EndJob", "JobFinished"]);
+        assert_eq!(report.information, vec![DeviceInformation::TemplateFailed]);
+        assert!(report.sent.is_empty());
+        Ok(())
+    }
+}
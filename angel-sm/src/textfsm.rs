@@ -0,0 +1,436 @@
+//! A parser for [TextFSM](https://github.com/google/textfsm/wiki/TextFSM) templates,
+//! which turn semi-structured CLI output into a table.
+
+use color_eyre::eyre::eyre;
+use regex::Regex;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Default)]
+struct ValueOptions {
+    filldown: bool,
+    fillup: bool,
+    required: bool,
+    list: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Value {
+    name: String,
+    regex: String,
+    options: ValueOptions,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+enum LineOp {
+    #[default]
+    Next,
+    Continue,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+enum RecordOp {
+    #[default]
+    NoRecord,
+    Record,
+    Clear,
+    ClearAll,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    regex: Regex,
+    line_op: LineOp,
+    record_op: RecordOp,
+    new_state: Option<String>,
+    /// Stop parsing with this message.
+    error: Option<String>,
+}
+
+/// A parsed TextFSM template.
+#[derive(Clone, Debug)]
+pub struct Template {
+    values: Vec<Value>,
+    states: BTreeMap<String, Vec<Rule>>,
+}
+
+/// The rows produced by a template. Every cell holds all values of its column, so
+/// it is empty if the column was never matched and can hold several for `List` values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<Vec<String>>>,
+}
+
+impl Table {
+    /// The rows as maps from column to its values joined by `, `, leaving out empty cells.
+    pub fn records(&self) -> Vec<BTreeMap<String, String>> {
+        self.rows
+            .iter()
+            .map(|row| {
+                self.header
+                    .iter()
+                    .zip(row.iter())
+                    .filter(|(_, cell)| !cell.is_empty())
+                    .map(|(name, cell)| (name.clone(), cell.join(", ")))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn parse_value(line: &str) -> color_eyre::Result<Value> {
+    let rest = line.trim_start_matches("Value").trim_start();
+    let (first, after) = rest
+        .split_once(' ')
+        .ok_or_else(|| eyre!("value needs a name and a regex: {line}"))?;
+    let after = after.trim_start();
+    let (options, name, regex) = if after.starts_with('(') {
+        ("", first, after)
+    } else {
+        let (name, regex) = after
+            .split_once(' ')
+            .ok_or_else(|| eyre!("value needs a name and a regex: {line}"))?;
+        (first, name, regex.trim_start())
+    };
+    if !regex.starts_with('(') || !regex.ends_with(')') {
+        return Err(eyre!("value regex has to be enclosed in parentheses: {line}"));
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(eyre!("invalid value name {name:?}"));
+    }
+
+    let mut o = ValueOptions::default();
+    for option in options.split(',').filter(|o| !o.is_empty()) {
+        match option {
+            "Filldown" => o.filldown = true,
+            "Fillup" => o.fillup = true,
+            "Required" => o.required = true,
+            "List" => o.list = true,
+            // Only used to join tables, which we don't do.
+            "Key" => {}
+            _ => return Err(eyre!("unknown value option {option}")),
+        }
+    }
+    Ok(Value {
+        name: name.to_string(),
+        regex: regex.to_string(),
+        options: o,
+    })
+}
+
+/// Replace `${name}` and `$name` by the value's regex, and `$$` by `$`.
+fn substitute(pattern: &str, values: &[Value]) -> color_eyre::Result<String> {
+    let mut out = String::new();
+    let mut rest = pattern;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let name = if let Some(r) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = r;
+            continue;
+        } else if let Some(r) = rest.strip_prefix('{') {
+            let end = r.find('}').ok_or_else(|| eyre!("unterminated variable in {pattern:?}"))?;
+            rest = &r[end + 1..];
+            &r[..end]
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+        let value = values
+            .iter()
+            .find(|v| v.name == name)
+            .ok_or_else(|| eyre!("unknown value {name:?} in {pattern:?}"))?;
+        out.push_str(&format!("(?P<{}>{})", value.name, value.regex));
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn parse_rule(line: &str, values: &[Value]) -> color_eyre::Result<Rule> {
+    let (pattern, action) = match line.rfind(" ->") {
+        Some(i) => (line[..i].trim_end(), line[i + 3..].trim()),
+        None => (line, ""),
+    };
+    let regex = Regex::new(&substitute(pattern, values)?)?;
+    let mut rule = Rule {
+        regex,
+        line_op: LineOp::Next,
+        record_op: RecordOp::NoRecord,
+        new_state: None,
+        error: None,
+    };
+
+    if let Some(message) = action.strip_prefix("Error") {
+        rule.error = Some(match message.trim() {
+            "" => "error rule matched".to_string(),
+            m => m.trim_matches('"').to_string(),
+        });
+        return Ok(rule);
+    }
+
+    let mut tokens = action.split_whitespace();
+    let mut token = tokens.next();
+    if let Some(ops) = token {
+        let mut known = true;
+        for op in ops.split('.') {
+            match op {
+                "Next" => rule.line_op = LineOp::Next,
+                "Continue" => rule.line_op = LineOp::Continue,
+                "NoRecord" => rule.record_op = RecordOp::NoRecord,
+                "Record" => rule.record_op = RecordOp::Record,
+                "Clear" => rule.record_op = RecordOp::Clear,
+                "Clearall" => rule.record_op = RecordOp::ClearAll,
+                _ => known = false,
+            }
+        }
+        if known {
+            token = tokens.next();
+        } else if ops.contains('.') {
+            return Err(eyre!("unknown action {ops:?}"));
+        }
+    }
+    rule.new_state = token.map(|s| s.to_string());
+    if tokens.next().is_some() {
+        return Err(eyre!("too many actions in rule {line:?}"));
+    }
+    if rule.line_op == LineOp::Continue && rule.new_state.is_some() {
+        return Err(eyre!("a Continue rule can't change state: {line:?}"));
+    }
+    Ok(rule)
+}
+
+impl Template {
+    pub fn parse(template: &str) -> color_eyre::Result<Self> {
+        let mut lines = template.lines().enumerate().map(|(i, l)| (i + 1, l.trim_end()));
+        let mut values = Vec::new();
+        let mut states = BTreeMap::new();
+
+        for (n, line) in lines.by_ref() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            if line.is_empty() {
+                if values.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            if !line.starts_with("Value ") {
+                return Err(eyre!("line {n}: expected a value definition"));
+            }
+            let value = parse_value(line).map_err(|e| eyre!("line {n}: {e}"))?;
+            if values.iter().any(|v: &Value| v.name == value.name) {
+                return Err(eyre!("line {n}: duplicate value {}", value.name));
+            }
+            values.push(value);
+        }
+
+        let mut current: Option<String> = None;
+        for (n, line) in lines {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            if line.is_empty() {
+                current = None;
+                continue;
+            }
+            if !line.starts_with(char::is_whitespace) {
+                if current.is_some() {
+                    return Err(eyre!("line {n}: states have to be separated by an empty line"));
+                }
+                if states.insert(line.to_string(), Vec::new()).is_some() {
+                    return Err(eyre!("line {n}: duplicate state {line}"));
+                }
+                current = Some(line.to_string());
+                continue;
+            }
+            let state = current
+                .as_ref()
+                .ok_or_else(|| eyre!("line {n}: rule outside of a state"))?;
+            let rule = line.trim_start();
+            if !rule.starts_with('^') {
+                return Err(eyre!("line {n}: rules have to start with ^"));
+            }
+            let rule = parse_rule(rule, &values).map_err(|e| eyre!("line {n}: {e}"))?;
+            states.get_mut(state).expect("current state exists").push(rule);
+        }
+
+        if !states.contains_key("Start") {
+            return Err(eyre!("template has no Start state"));
+        }
+        for (state, rules) in states.iter() {
+            if (state == "End" || state == "EOF") && !rules.is_empty() {
+                return Err(eyre!("the {state} state can't have rules"));
+            }
+            for target in rules.iter().filter_map(|r| r.new_state.as_ref()) {
+                if target != "End" && target != "EOF" && !states.contains_key(target) {
+                    return Err(eyre!("state {state} goes to unknown state {target}"));
+                }
+            }
+        }
+        Ok(Self { values, states })
+    }
+
+    pub fn header(&self) -> Vec<String> {
+        self.values.iter().map(|v| v.name.clone()).collect()
+    }
+
+    /// Run the template over `text`, line by line.
+    pub fn parse_text(&self, text: &str) -> color_eyre::Result<Table> {
+        let mut run = Run {
+            template: self,
+            current: vec![Vec::new(); self.values.len()],
+            rows: Vec::new(),
+        };
+        let mut state = "Start";
+
+        'lines: for line in text.lines() {
+            let line = line.trim_end_matches('\r');
+            for rule in self.states[state].iter() {
+                let Some(cap) = rule.regex.captures(line) else {
+                    continue;
+                };
+                if let Some(error) = &rule.error {
+                    return Err(eyre!("{error} (line {line:?})"));
+                }
+                for (i, value) in self.values.iter().enumerate() {
+                    if let Some(m) = cap.name(&value.name) {
+                        run.assign(i, m.as_str());
+                    }
+                }
+                match rule.record_op {
+                    RecordOp::NoRecord => {}
+                    RecordOp::Record => run.record(),
+                    RecordOp::Clear => run.clear(false),
+                    RecordOp::ClearAll => run.clear(true),
+                }
+                if rule.line_op == LineOp::Continue {
+                    continue;
+                }
+                if let Some(new_state) = &rule.new_state {
+                    state = new_state;
+                    if state == "End" || state == "EOF" {
+                        break 'lines;
+                    }
+                }
+                continue 'lines;
+            }
+        }
+
+        // There is an implicit record at the end, unless an empty EOF state is defined.
+        if state != "End" && !self.states.contains_key("EOF") {
+            run.record();
+        }
+        Ok(Table {
+            header: self.header(),
+            rows: run.rows,
+        })
+    }
+}
+
+struct Run<'a> {
+    template: &'a Template,
+    current: Vec<Vec<String>>,
+    rows: Vec<Vec<Vec<String>>>,
+}
+
+impl Run<'_> {
+    fn assign(&mut self, i: usize, value: &str) {
+        let options = &self.template.values[i].options;
+        if options.list {
+            self.current[i].push(value.to_string());
+        } else {
+            self.current[i] = vec![value.to_string()];
+        }
+        if options.fillup {
+            for row in self.rows.iter_mut().rev() {
+                if !row[i].is_empty() {
+                    break;
+                }
+                row[i] = self.current[i].clone();
+            }
+        }
+    }
+
+    fn clear(&mut self, all: bool) {
+        for (value, current) in self.template.values.iter().zip(self.current.iter_mut()) {
+            if all || !value.options.filldown {
+                current.clear();
+            }
+        }
+    }
+
+    fn record(&mut self) {
+        let missing = self
+            .template
+            .values
+            .iter()
+            .zip(self.current.iter())
+            .any(|(v, c)| v.options.required && c.is_empty());
+        if missing {
+            self.clear(false);
+            return;
+        }
+        if self.current.iter().all(|c| c.is_empty()) {
+            return;
+        }
+        self.rows.push(self.current.clone());
+        self.clear(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str = r#"# Interfaces and their addresses
+Value Filldown Chassis (\S+)
+Value Required Interface (\S+)
+Value List Address (\d+\.\d+\.\d+\.\d+)
+Value Description (.*)
+
+Start
+  ^Chassis ${Chassis}
+  ^Interfaces: -> Interfaces
+
+Interfaces
+  ^  \S+ is up -> Continue.Record
+  ^  ${Interface} is up, ${Description}$$
+  ^    inet ${Address}
+  ^Done -> Record End
+  ^Oops -> Error "unexpected output"
+"#;
+
+    #[test]
+    fn parse() -> color_eyre::Result<()> {
+        let template = Template::parse(TEMPLATE)?;
+        let output = "Chassis sw01\r\nInterfaces:\r\n  ge-0/0/0 is up, uplink\r\n    inet 10.0.0.1\r\n    inet 10.0.1.1\r\n  ge-0/0/1 is up, \r\nDone\r\n  ge-0/0/2 is up, ignored\r\n";
+        let table = template.parse_text(output)?;
+        assert_eq!(table.header, vec!["Chassis", "Interface", "Address", "Description"]);
+        assert_eq!(
+            table.records(),
+            vec![
+                BTreeMap::from([
+                    ("Chassis".to_string(), "sw01".to_string()),
+                    ("Interface".to_string(), "ge-0/0/0".to_string()),
+                    ("Address".to_string(), "10.0.0.1, 10.0.1.1".to_string()),
+                    ("Description".to_string(), "uplink".to_string()),
+                ]),
+                BTreeMap::from([
+                    ("Chassis".to_string(), "sw01".to_string()),
+                    ("Interface".to_string(), "ge-0/0/1".to_string()),
+                    ("Description".to_string(), "".to_string()),
+                ]),
+            ]
+        );
+
+        assert!(template.parse_text("Interfaces:\nOops\n").is_err());
+        assert!(Template::parse("Value Foo (\\S+)\n\nStart\n  ^${Bar}\n").is_err());
+        Ok(())
+    }
+}
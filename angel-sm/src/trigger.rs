@@ -1,24 +1,498 @@
-use crate::data_structure::StateMachineTrigger;
+use crate::data_structure::{StateMachineTrigger, TriggerAnchor, TriggerOptions};
+use color_eyre::eyre::eyre;
 use regex::Regex;
+use regex_syntax::hir::{Hir, HirKind};
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+use std::time::{Duration, Instant};
 use swexpect::hay::ReadUntil;
 
+/// How much of the end of the last sent line has to be echoed. Long lines get wrapped
+/// by the device, so only the tail is looked for.
+const ECHO_TAIL: usize = 32;
+
+/// How long received text is kept around for composite triggers.
+pub const MAX_HISTORY_AGE: Duration = Duration::from_secs(600);
+
+/// How much received text is kept around for composite triggers.
+const MAX_HISTORY_BYTES: usize = 256 * 1024;
+
+/// Matches any number of ANSI escape sequences.
+const ANSI_ESCAPES: &str = r"(?:\x1b\[[0-9;?]*[A-Za-z])*";
+
+/// A trigger with its regex compiled, built once when the state machine is built.
+#[derive(Clone, Debug)]
+pub enum CompiledTrigger {
+    String(String),
+    Regex(Regex),
+    /// Only matches after the echo of the last sent line, see [`CompiledTrigger::after_echo`].
+    AfterEcho(Regex),
+    Immediate,
+    Timeout(Duration),
+    AllOf(Vec<CompiledTrigger>, Duration),
+    Sequence(Vec<CompiledTrigger>, Duration),
+    NotWithin(Box<CompiledTrigger>, Box<CompiledTrigger>, Duration),
+}
+
+/// Text recently received from the device, for composite triggers. Timestamps are kept per
+/// match: the matched text at the time it was found, and the data before it at the time of the
+/// previous match, the earliest it can have arrived. Windows are only as precise as the matches
+/// are frequent.
+#[derive(Clone, Debug, Default)]
+pub struct ReceiveHistory {
+    chunks: VecDeque<(Instant, String)>,
+    bytes: usize,
+    last_match: Option<Instant>,
+}
+
+impl ReceiveHistory {
+    /// Add a match and the data received before it.
+    pub fn push(&mut self, data: &str, mat: &str) {
+        let now = Instant::now();
+        self.add(self.last_match.unwrap_or(now), data);
+        self.add(now, mat);
+        self.last_match = Some(now);
+        while let Some((t, c)) = self.chunks.front() {
+            if self.bytes <= MAX_HISTORY_BYTES && now.duration_since(*t) <= MAX_HISTORY_AGE {
+                break;
+            }
+            self.bytes -= c.len();
+            self.chunks.pop_front();
+        }
+    }
+
+    fn add(&mut self, at: Instant, text: &str) {
+        if !text.is_empty() {
+            self.bytes += text.len();
+            self.chunks.push_back((at, text.to_string()));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.bytes = 0;
+        self.last_match = Some(Instant::now());
+    }
+
+    /// Everything received within the last `window`.
+    pub fn since(&self, window: Duration) -> String {
+        let now = Instant::now();
+        self.chunks
+            .iter()
+            .filter(|(t, _)| now.duration_since(*t) <= window)
+            .map(|(_, c)| c.as_str())
+            .collect()
+    }
+}
+
+/// Regexes built by [`CompiledTrigger::after_echo`], by trigger regex, kept until another
+/// line is sent.
+#[derive(Clone, Debug, Default)]
+pub struct EchoRegexes {
+    line: Option<String>,
+    regexes: BTreeMap<String, Option<Regex>>,
+}
+
+impl EchoRegexes {
+    pub fn get(
+        &mut self,
+        trigger: &CompiledTrigger,
+        last_sent: Option<&str>,
+    ) -> color_eyre::Result<Option<Regex>> {
+        let CompiledTrigger::AfterEcho(r) = trigger else {
+            return Ok(None);
+        };
+        if self.line.as_deref() != last_sent {
+            self.line = last_sent.map(str::to_string);
+            self.regexes.clear();
+        }
+        if let Some(echo) = self.regexes.get(r.as_str()) {
+            return Ok(echo.clone());
+        }
+        let echo = trigger.after_echo(last_sent)?;
+        self.regexes.insert(r.as_str().to_string(), echo.clone());
+        Ok(echo)
+    }
+}
+
+/// Allow ANSI escape sequences between any two characters matched by `hir`.
+fn ansi_tolerant(hir: Hir, ansi: &Hir) -> Hir {
+    let interleave = |subs: Vec<Hir>| {
+        let mut out = Vec::with_capacity(subs.len() * 2);
+        for (i, sub) in subs.into_iter().enumerate() {
+            if i > 0 {
+                out.push(ansi.clone());
+            }
+            out.push(sub);
+        }
+        Hir::concat(out)
+    };
+
+    match hir.into_kind() {
+        HirKind::Literal(lit) => match std::str::from_utf8(&lit.0) {
+            Ok(s) => interleave(
+                s.chars()
+                    .map(|c| Hir::literal(c.to_string().into_bytes()))
+                    .collect(),
+            ),
+            Err(_) => Hir::literal(lit.0),
+        },
+        HirKind::Concat(subs) => {
+            interleave(subs.into_iter().map(|s| ansi_tolerant(s, ansi)).collect())
+        }
+        HirKind::Alternation(subs) => {
+            Hir::alternation(subs.into_iter().map(|s| ansi_tolerant(s, ansi)).collect())
+        }
+        HirKind::Repetition(mut rep) => {
+            rep.sub = Box::new(Hir::concat(vec![ansi_tolerant(*rep.sub, ansi), ansi.clone()]));
+            Hir::repetition(rep)
+        }
+        HirKind::Capture(mut cap) => {
+            cap.sub = Box::new(ansi_tolerant(*cap.sub, ansi));
+            Hir::capture(cap)
+        }
+        HirKind::Class(class) => Hir::class(class),
+        HirKind::Look(look) => Hir::look(look),
+        HirKind::Empty => Hir::empty(),
+    }
+}
+
+/// Turn a pattern and its options into a single regex pattern.
+fn apply_options(pattern: &str, options: &TriggerOptions) -> color_eyre::Result<String> {
+    let mut pattern = match options.anchor {
+        None => format!("(?:{pattern})"),
+        Some(TriggerAnchor::LineStart) => format!("(?mR:^)(?:{pattern})"),
+        Some(TriggerAnchor::LineEnd) => format!("(?:{pattern})(?mR:$)"),
+        Some(TriggerAnchor::Line) => format!("(?mR:^)(?:{pattern})(?mR:$)"),
+    };
+    if options.case_insensitive {
+        pattern = format!("(?i:{pattern})");
+    }
+    if options.strip_ansi {
+        let hir = regex_syntax::parse(&pattern)?;
+        let ansi = regex_syntax::parse(ANSI_ESCAPES)?;
+        pattern = ansi_tolerant(hir, &ansi).to_string();
+    }
+    Ok(pattern)
+}
+
+/// Compile the parts of a composite trigger, which have to be string or regex triggers.
+fn compile_parts(triggers: &[StateMachineTrigger]) -> color_eyre::Result<Vec<CompiledTrigger>> {
+    if triggers.is_empty() {
+        return Err(eyre!("composite trigger without any triggers"));
+    }
+    triggers
+        .iter()
+        .map(|t| match t {
+            StateMachineTrigger::String { .. } | StateMachineTrigger::Regex { .. } => t.compile(),
+            _ => Err(eyre!("composite triggers can only be made of string and regex triggers")),
+        })
+        .collect()
+}
+
 impl StateMachineTrigger {
-    pub fn to_needle(&self) -> color_eyre::Result<Option<ReadUntil>> {
+    pub fn compile(&self) -> color_eyre::Result<CompiledTrigger> {
+        let (pattern, options) = match self {
+            StateMachineTrigger::String { string, options } if *options == TriggerOptions::default() => {
+                return Ok(CompiledTrigger::String(string.clone()));
+            }
+            StateMachineTrigger::String { string, options } => (regex::escape(string), options),
+            StateMachineTrigger::Regex { regex, options } => (regex.clone(), options),
+            StateMachineTrigger::Immediate => return Ok(CompiledTrigger::Immediate),
+            StateMachineTrigger::Timeout { duration } => return Ok(CompiledTrigger::Timeout(*duration)),
+            StateMachineTrigger::AllOf { triggers, window } => {
+                return Ok(CompiledTrigger::AllOf(compile_parts(triggers)?, *window));
+            }
+            StateMachineTrigger::Sequence { triggers, window } => {
+                return Ok(CompiledTrigger::Sequence(compile_parts(triggers)?, *window));
+            }
+            StateMachineTrigger::NotWithin { trigger, not, window } => {
+                let mut parts = compile_parts(&[*trigger.clone(), *not.clone()])?;
+                let not = parts.pop().unwrap();
+                let trigger = parts.pop().unwrap();
+                return Ok(CompiledTrigger::NotWithin(Box::new(trigger), Box::new(not), *window));
+            }
+        };
+        let regex = Regex::new(&apply_options(&pattern, options)?)?;
+        if options.ignore_echo {
+            Ok(CompiledTrigger::AfterEcho(regex))
+        } else {
+            Ok(CompiledTrigger::Regex(regex))
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            StateMachineTrigger::Timeout { duration } => Some(*duration),
+            _ => None,
+        }
+    }
+}
+
+impl CompiledTrigger {
+    /// What to wait for on the serial port. A composite trigger waits for the parts that can complete it.
+    pub fn needles(&self) -> Vec<ReadUntil> {
+        match self {
+            CompiledTrigger::String(s) => vec![ReadUntil::String(s.clone())],
+            CompiledTrigger::Regex(r) | CompiledTrigger::AfterEcho(r) => vec![ReadUntil::Regex(r.clone())],
+            CompiledTrigger::Immediate => vec![],
+            CompiledTrigger::Timeout(_) => vec![],
+            CompiledTrigger::AllOf(parts, _) => parts.iter().flat_map(|t| t.needles()).collect(),
+            CompiledTrigger::Sequence(parts, _) => parts.last().map(|t| t.needles()).unwrap_or_default(),
+            CompiledTrigger::NotWithin(trigger, _, _) => trigger.needles(),
+        }
+    }
+
+    pub fn matches_result(&self, m: &str) -> bool {
+        match self {
+            CompiledTrigger::String(s) => m == s,
+            CompiledTrigger::Regex(r) | CompiledTrigger::AfterEcho(r) => r.is_match(m),
+            CompiledTrigger::Immediate => true,
+            CompiledTrigger::Timeout(_) => false,
+            CompiledTrigger::AllOf(parts, _) => parts.iter().any(|t| t.matches_result(m)),
+            CompiledTrigger::Sequence(parts, _) => parts.last().is_some_and(|t| t.matches_result(m)),
+            CompiledTrigger::NotWithin(trigger, _, _) => trigger.matches_result(m),
+        }
+    }
+
+    /// Find the first match of a string or regex trigger in `text`.
+    fn find(&self, text: &str) -> Option<Range<usize>> {
         match self {
-            StateMachineTrigger::String { string: s } => Ok(Some(ReadUntil::String(s.clone()))),
-            StateMachineTrigger::Regex { regex: s } => Ok(Some(ReadUntil::Regex(Regex::new(s)?))),
-            StateMachineTrigger::Immediate => Ok(None),
+            CompiledTrigger::String(s) => text.find(s.as_str()).map(|i| i..i + s.len()),
+            CompiledTrigger::Regex(r) | CompiledTrigger::AfterEcho(r) => r.find(text).map(|m| m.range()),
+            _ => None,
         }
     }
 
-    pub fn matches_result(&self, m: &str) -> color_eyre::Result<bool> {
+    /// Whether the conditions of a composite trigger hold for the text received recently.
+    /// Other triggers only depend on the match itself, so they always hold.
+    pub fn holds_in(&self, history: &ReceiveHistory) -> bool {
         match self {
-            StateMachineTrigger::String { string: s } => Ok(m == s),
-            StateMachineTrigger::Regex { regex: s } => {
-                let r = Regex::new(s)?;
-                Ok(r.is_match(m))
+            CompiledTrigger::AllOf(parts, window) => {
+                let text = history.since(*window);
+                parts.iter().all(|t| t.find(&text).is_some())
+            }
+            CompiledTrigger::Sequence(parts, window) => {
+                let text = history.since(*window);
+                let mut pos = 0;
+                for t in parts.iter() {
+                    match t.find(&text[pos..]) {
+                        Some(r) => pos += r.end,
+                        None => return false,
+                    }
+                }
+                true
             }
-            StateMachineTrigger::Immediate => Ok(true),
+            CompiledTrigger::NotWithin(_, not, window) => not.find(&history.since(*window)).is_none(),
+            _ => true,
+        }
+    }
+
+    pub fn is_immediate(&self) -> bool {
+        matches!(self, CompiledTrigger::Immediate)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            CompiledTrigger::Timeout(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    /// For triggers that ignore the echo, a regex that first skips everything up to the
+    /// echo of `last_sent`, with the trigger itself in capture group 1.
+    pub fn after_echo(&self, last_sent: Option<&str>) -> color_eyre::Result<Option<Regex>> {
+        let CompiledTrigger::AfterEcho(r) = self else {
+            return Ok(None);
+        };
+        let Some(line) = last_sent.map(str::trim).filter(|l| !l.is_empty()) else {
+            return Ok(None);
+        };
+        let tail: String = line
+            .chars()
+            .rev()
+            .take(ECHO_TAIL)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+        Regex::new(&format!("(?s:{}.*?)({})", regex::escape(&tail), r.as_str()))
+            .map(Some)
+            .map_err(|e| eyre!("unable to build echo regex: {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StateMachineBuilder;
+    use crate::replay::replay_log;
+    use std::sync::Arc;
+
+    fn compile(regex: &str, options: TriggerOptions) -> Regex {
+        match (StateMachineTrigger::Regex {
+            regex: regex.to_string(),
+            options,
+        })
+        .compile()
+        .unwrap()
+        {
+            CompiledTrigger::Regex(r) | CompiledTrigger::AfterEcho(r) => r,
+            t => panic!("unexpected trigger {t:?}"),
+        }
+    }
+
+    #[test]
+    fn options() {
+        let ansi = compile(
+            "root(@[a-z]+)?>",
+            TriggerOptions {
+                strip_ansi: true,
+                case_insensitive: true,
+                ..Default::default()
+            },
+        );
+        assert!(ansi.is_match("\x1b[1mRoot\x1b[0m@sw\x1b[0mitch>"));
+        assert!(!ansi.is_match("root@>"));
+
+        let anchored = compile(
+            "=>",
+            TriggerOptions {
+                anchor: Some(TriggerAnchor::Line),
+                ..Default::default()
+            },
+        );
+        assert!(anchored.is_match("noise\r\n=>\r\n"));
+        assert!(!anchored.is_match("a => b\r\n"));
+
+        let echo = CompiledTrigger::AfterEcho(Regex::new("root>").unwrap())
+            .after_echo(Some("show version | match root>"))
+            .unwrap()
+            .unwrap();
+        let m = echo.captures("root> show version | match root>\r\nfoo\r\nroot> ").unwrap();
+        assert_eq!(m.get(1).unwrap().start(), 39);
+    }
+
+    #[test]
+    fn composite() {
+        let string = |s: &str| StateMachineTrigger::String {
+            string: s.to_string(),
+            options: Default::default(),
+        };
+        let window = Duration::from_secs(5);
+        let sequence = StateMachineTrigger::Sequence {
+            triggers: vec![string("U-Boot 2010.03"), string("scanning bus")],
+            window,
         }
+        .compile()
+        .unwrap();
+        let not_within = StateMachineTrigger::NotWithin {
+            trigger: Box::new(string("login:")),
+            not: Box::new(string("Amnesiac")),
+            window,
+        }
+        .compile()
+        .unwrap();
+
+        let mut history = ReceiveHistory::default();
+        history.push("scanning bus\r\nU-Boot 2010.03\r\n", "login:");
+        assert!(!sequence.holds_in(&history));
+        assert!(not_within.holds_in(&history));
+        history.push("\r\nscanning bus\r\n", "Amnesiac (ttyu0)");
+        assert!(sequence.holds_in(&history));
+        assert!(!not_within.holds_in(&history));
+        history.clear();
+        assert!(not_within.holds_in(&history));
+
+        assert!(
+            StateMachineTrigger::AllOf {
+                triggers: vec![StateMachineTrigger::Immediate],
+                window,
+            }
+            .compile()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn history_timestamps() {
+        let window = Duration::from_millis(200);
+        let mut history = ReceiveHistory::default();
+        history.push("U-Boot 2010.03\r\n", "login:");
+        assert_eq!(history.since(window), "U-Boot 2010.03\r\nlogin:");
+
+        // Data is only known to have arrived after the previous match.
+        std::thread::sleep(window * 2);
+        history.push("\r\nscanning bus\r\n", "Amnesiac");
+        assert_eq!(history.since(window), "Amnesiac");
+        assert_eq!(
+            history.since(window * 4),
+            "U-Boot 2010.03\r\nlogin:\r\nscanning bus\r\nAmnesiac"
+        );
+
+        std::thread::sleep(window * 2);
+        history.push("", "root>");
+        assert_eq!(history.since(window), "root>");
+        assert_eq!(history.since(window * 3), "Amnesiacroot>");
+    }
+
+    const ECHO_STATES: &str = r#"
+id = "echo_test"
+
+state "SwitchDetect" {
+  merge = "append"
+  transition {
+    target = "EchoTestVersion"
+    trigger {
+      type   = "string"
+      string = "start"
+    }
+    action {
+      type = "SendLine"
+      line = "show version"
+    }
+  }
+}
+
+state "EchoTestVersion" {
+  transition {
+    target = "EchoTestPrompt"
+    trigger {
+      type        = "string"
+      string      = "root>"
+      ignore_echo = true
+    }
+  }
+}
+
+state "EchoTestPrompt" {
+  transition {
+    target = "EndJob"
+    trigger {
+      type        = "string"
+      string      = "root>"
+      ignore_echo = true
+    }
+  }
+}
+"#;
+
+    #[tokio::test]
+    async fn echo_is_consumed() -> color_eyre::Result<()> {
+        let mut builder = StateMachineBuilder::new();
+        builder.load_state_file(hcl::from_str(ECHO_STATES)?);
+        builder.activate_state_file("echo_test")?;
+        let sm = builder.build()?;
+
+        // Nothing was sent after the first prompt, so the second one does not wait for an echo.
+        let log = "start\r\nroot> show version\r\nJunos 1\r\nroot> \r\nroot> ";
+        let report = replay_log(Arc::new(sm), log.as_bytes(), BTreeMap::new()).await?;
+
+        assert!(report.finished(), "{report:?}");
+        assert_eq!(
+            report.states,
+            vec!["Init", "SwitchDetect", "EchoTestVersion", "EchoTestPrompt", "EndJob", "JobFinished"]
+        );
+        Ok(())
     }
 }
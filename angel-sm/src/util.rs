@@ -1,5 +1,5 @@
 use std::time::Duration;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serializer};
 
 #[derive(Deserialize)]
 #[serde(untagged)]
@@ -27,4 +27,12 @@ where
     let d = f64::deserialize(deserializer)?;
     let c = Duration::from_secs_f64(d);
     Ok(c)
-}
\ No newline at end of file
+}
+
+/// Durations are written as seconds, like [`deser_duration`] reads them.
+pub fn ser_duration<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_f64(duration.as_secs_f64())
+}
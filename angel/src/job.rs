@@ -1,29 +1,27 @@
 use crate::logging::TracingTarget;
 use crate::mqtt::MQTTSender;
+use crate::secrets::SecretMasker;
 use chrono::Utc;
-use color_eyre::eyre::Context;
 use cthulhu_angel_sm::AngelJob;
-use cthulhu_angel_sm::data_structure::{State, StateMachineTransition, StateMachineTrigger};
-use cthulhu_angel_sm::state::StateMachine;
 use cthulhu_common::devinfo::DeviceInformation;
-use cthulhu_common::job::JobData;
+use cthulhu_common::job::{JobData, StageMetadata, StateMachineManifest};
 use cthulhu_common::status::JobUpdate;
 use std::collections::BTreeMap;
 use std::path::PathBuf;
-use swexpect::SwitchExpect;
-use swexpect::hay::ReadUntil;
-use tracing::{debug, info, warn};
+use tracing::info;
 
 pub struct ActiveJob {
     pub data: JobData,
-    state_machine: StateMachine,
     shutdown_requested: bool,
-    current_state: State,
     pub mqtt: MQTTSender,
     tracing_target: TracingTarget,
     rawlog_target: TracingTarget,
     log_dir: Option<PathBuf>,
     job_config: BTreeMap<String, String>,
+    secrets: BTreeMap<String, String>,
+    /// Masks secrets in variables, which are logged and published.
+    masker: SecretMasker,
+    last_sent_line: Option<String>,
 }
 
 impl AngelJob for ActiveJob {
@@ -66,14 +64,15 @@ impl AngelJob for ActiveJob {
         info!("Resetting job...");
         //TODO: Maybe send a JobEnd sometimes?
 
-        self.current_state = "Init".to_string();
         self.data.reset();
+        self.last_sent_line = None;
         self.send_update(JobUpdate::JobStart(Utc::now())).await?;
-        self.send_update(JobUpdate::JobStageTransition(
-            Utc::now(),
-            self.current_state.clone(),
-        ))
-        .await?;
+        Ok(())
+    }
+
+    async fn enter_state(&mut self, state: &str, metadata: StageMetadata) -> color_eyre::Result<()> {
+        self.send_update(JobUpdate::JobStageTransition(Utc::now(), state.to_string(), metadata))
+            .await?;
         Ok(())
     }
 
@@ -84,9 +83,41 @@ impl AngelJob for ActiveJob {
         Ok(())
     }
 
+    async fn get_information(&self) -> Vec<DeviceInformation> {
+        self.data.info_items.iter().cloned().collect()
+    }
+
     async fn get_job_config_key(&self, key: &str) -> Option<String> {
         self.job_config.get(key).cloned()
     }
+
+    async fn get_secret(&self, name: &str) -> Option<String> {
+        self.secrets.get(name).cloned()
+    }
+
+    async fn get_variables(&self) -> BTreeMap<String, String> {
+        self.data.variables.clone()
+    }
+
+    async fn set_variable(&mut self, name: &str, value: String) -> color_eyre::Result<()> {
+        let value = self.masker.mask_str(&value);
+        info!("Set job variable {name} = {value:?}");
+        self.send_update(JobUpdate::JobVariable(name.to_string(), value))
+            .await?;
+        Ok(())
+    }
+
+    async fn record_sent_line(&mut self, line: &str) {
+        self.last_sent_line = Some(line.to_string());
+    }
+
+    async fn last_sent_line(&self) -> Option<String> {
+        self.last_sent_line.clone()
+    }
+
+    async fn clear_sent_line(&mut self) {
+        self.last_sent_line = None;
+    }
 }
 
 impl ActiveJob {
@@ -95,90 +126,23 @@ impl ActiveJob {
         log_dir: Option<PathBuf>,
         tracing_target: TracingTarget,
         rawlog_target: TracingTarget,
-        state_machine: StateMachine,
         job_config: BTreeMap<String, String>,
+        secrets: BTreeMap<String, String>,
     ) -> Self {
         Self {
             data: JobData::with_label(mqtt.id()),
-            current_state: "Init".to_string(),
             mqtt,
             log_dir,
             tracing_target,
             rawlog_target,
-            state_machine,
             job_config,
+            masker: SecretMasker::new(secrets.values().cloned()),
+            secrets,
+            last_sent_line: None,
             shutdown_requested: false,
         }
     }
 
-    async fn transition(
-        &mut self,
-        t: &StateMachineTransition,
-        p: &mut SwitchExpect,
-        d: &str,
-        m: &str,
-    ) -> color_eyre::Result<()> {
-        // Validate that the state exists
-        let _ = self.state_machine.state(&t.target)?;
-
-        let old_state = self.current_state.clone();
-        self.current_state = t.target.clone();
-        info!("State transition: {:?} -> {:?}", old_state, t.target);
-        self.send_update(JobUpdate::JobStageTransition(Utc::now(), t.target.clone()))
-            .await?;
-        for action in &t.actions {
-            action.perform(self, p, d, m).await?;
-        }
-
-        let cycles = self.data.state_history.iter().map(|(_, a)| a.as_str()).filter(|&s| s == self.current_state.as_str()).count();
-        if cycles > 5 {
-            warn!("Loop detected! Ending job...");
-            self.add_information(DeviceInformation::LoopDetected).await?;
-            self.current_state = "EndJob".to_string();
-            self.send_update(JobUpdate::JobStageTransition(Utc::now(), self.current_state.clone()))
-                .await?;
-        }
-        Ok(())
-    }
-
-    pub async fn step(&mut self, p: &mut SwitchExpect) -> color_eyre::Result<()> {
-        let s = self.state_machine.state(&self.current_state)?;
-        let transitions = &s.transitions;
-
-        if let Some(t) = transitions
-            .iter()
-            .find(|t| t.trigger == StateMachineTrigger::Immediate)
-        {
-            self.transition(t, p, "", "")
-                .await
-                .context("process immediate transition")?;
-        } else {
-            let u = ReadUntil::Any(
-                transitions
-                    .iter()
-                    .map(|t| t.trigger.to_needle().map(|v| v.unwrap()))
-                    .collect::<color_eyre::Result<Vec<_>>>()?,
-            );
-
-            // Try to handle a result from the switches.
-            debug!("Waiting for needle {u:?}...");
-            let (d, m) = p
-                .expect(&u)
-                .await
-                .context("failed to read from serial port")?;
-            't_test: for t in transitions.iter() {
-                if t.trigger.matches_result(&m)? {
-                    self.transition(&t, p, &d, &m)
-                        .await
-                        .context("process serial transition")?;
-                    break 't_test;
-                }
-            }
-        }
-
-        Ok(())
-    }
-
     pub async fn flag_restart(&mut self) -> color_eyre::Result<()> {
         if self.data.get_status().is_idle() {
             panic!("Crash requested!");
@@ -187,6 +151,11 @@ impl ActiveJob {
         Ok(())
     }
 
+    /// Tell heaven which state machine this angel built.
+    pub async fn set_state_machine(&mut self, manifest: StateMachineManifest) -> color_eyre::Result<()> {
+        self.send_update(JobUpdate::StateMachine(manifest)).await
+    }
+
     async fn send_update(&mut self, update: JobUpdate) -> color_eyre::Result<()> {
         self.data.update(update.clone());
         self.mqtt.send_update(update).await?;
@@ -1,3 +1,4 @@
+use crate::secrets::SecretMasker;
 use cthulhu_config::angel::AngelConfig;
 use pin_project::pin_project;
 use std::fs::File;
@@ -23,17 +24,24 @@ pub struct SerialLogger<IO: SerialIO> {
     #[pin]
     stream: IO,
     buffer: String,
+    masker: SecretMasker,
 }
 
 impl<IO: SerialIO> SerialLogger<IO> {
-    pub fn new(stream: IO) -> Self {
+    pub fn new(stream: IO, masker: SecretMasker) -> Self {
         Self {
             stream,
             buffer: String::new(),
+            masker,
         }
     }
 }
 
+/// Escape sequences are stripped before masking, so they cannot hide a secret from the masker.
+fn serial_log_line(masker: &SecretMasker, line: &str) -> String {
+    masker.mask_str(&strip_ansi_escapes::strip_str(line.trim_end()))
+}
+
 impl<IO: SerialIO> AsyncRead for SerialLogger<IO> {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -48,9 +56,7 @@ impl<IO: SerialIO> AsyncRead for SerialLogger<IO> {
         me.buffer.extend(String::from_utf8_lossy(data).chars());
         while let Some(pos) = me.buffer.find('\n') {
             let line: String = me.buffer.drain(..=pos).collect();
-            let p = line.trim_end();
-            let p = strip_ansi_escapes::strip_str(p);
-            info!("Serial: {p}");
+            info!("Serial: {}", serial_log_line(me.masker, &line));
         }
 
         Poll::Ready(Ok(()))
@@ -128,7 +134,7 @@ impl<'a> MakeWriter<'a> for TracingTarget {
 
 pub async fn setup_tracing(config: &AngelConfig) -> color_eyre::Result<TracingTarget> {
     let max_log_level =
-        Level::from_str(&(config.log_level.as_ref().unwrap_or(&"info".to_string())))?;
+        Level::from_str(config.log_level.as_ref().unwrap_or(&"info".to_string()))?;
     let target = TracingTarget {
         target: Arc::new(Mutex::new(None)),
     };
@@ -143,8 +149,11 @@ pub async fn setup_tracing(config: &AngelConfig) -> color_eyre::Result<TracingTa
     Ok(target)
 }
 
+/// The raw log keeps the serial data byte for byte, so it is masked without stripping escape
+/// sequences. A secret the device prints interrupted by escape sequences stays in the raw log.
 pub async fn wrap_raw_serial_log<IO: 'static + AsyncRead + AsyncWrite + Unpin + Send + Sync>(
     inp: IO,
+    masker: SecretMasker,
 ) -> color_eyre::Result<(
     impl 'static + AsyncRead + AsyncWrite + Unpin + Send + Sync,
     TracingTarget,
@@ -154,11 +163,24 @@ pub async fn wrap_raw_serial_log<IO: 'static + AsyncRead + AsyncWrite + Unpin +
     };
     let io = {
         let target = target.clone();
+        let mut masked = masker.stream();
         InspectReader::new(inp, move |d| {
             let mut writer = target.make_writer();
-            writer.write_all(d).unwrap();
+            writer.write_all(&masked.push(d)).unwrap();
             writer.flush().unwrap();
         })
     };
     Ok((io, target))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_after_stripping_escapes() {
+        let masker = SecretMasker::new(["hunter2".to_string()]);
+        assert_eq!(serial_log_line(&masker, "password: hun\x1b[1mter2\r\n"), "password: ***");
+        assert_eq!(serial_log_line(&masker, "\x1b[32mhunter2\x1b[0m"), "***");
+    }
+}
@@ -3,15 +3,22 @@ use crate::job::ActiveJob;
 use crate::logging::{SerialLogger, setup_tracing, wrap_raw_serial_log};
 use crate::mqtt::{MQTTSender, create_mqtt_sender_from_config, wrap_mqtt_serial_log};
 use crate::ports::port_from_config;
+use crate::secrets::SecretMasker;
 use clap::Parser;
-use color_eyre::eyre::eyre;
-use cthulhu_angel_sm::AngelJob;
+use color_eyre::eyre::{WrapErr, eyre};
 use cthulhu_angel_sm::builder::StateMachineBuilder;
+use cthulhu_angel_sm::lint::{LintConfig, LintSeverity};
+use cthulhu_angel_sm::profile::{Profile, ProfileRules, check_profiles};
+use cthulhu_angel_sm::runner::StateMachineRunner;
+use cthulhu_angel_sm::state::StateMachine;
+use cthulhu_common::job::StateMachineManifest;
 use cthulhu_common::status::{JobCommand, JobUpdate};
 use cthulhu_config::angel::AngelConfig;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
 use swexpect::SwitchExpect;
 use tokio::sync::mpsc;
-use tracing::info;
+use tracing::{error, info};
 use cthulhu_config::LoadableConfig;
 
 mod args;
@@ -19,6 +26,36 @@ mod job;
 mod logging;
 mod mqtt;
 mod ports;
+mod secrets;
+
+fn build_state_machine(config: &AngelConfig, active_states: &[String]) -> color_eyre::Result<StateMachine> {
+    let mut smb = StateMachineBuilder::new();
+    smb.load_builtin_state_files()?;
+    for dir in config.state_dirs.iter() {
+        smb.load_state_files_from_dir(dir)?;
+    }
+    for id in active_states.iter() {
+        smb.activate_state_file(id)?;
+    }
+
+    // Values missing from the config would otherwise only show up halfway through a job.
+    let secrets: BTreeSet<_> = config.secrets.keys().cloned().collect();
+    let errors: Vec<_> = smb
+        .lint(Some(LintConfig {
+            job_config: &config.job_config,
+            secrets: &secrets,
+        }))?
+        .into_iter()
+        .filter(|i| i.severity == LintSeverity::Error)
+        .collect();
+    for issue in errors.iter() {
+        error!("{issue}");
+    }
+    if !errors.is_empty() {
+        return Err(eyre!("state machine does not fit the angel config"));
+    }
+    smb.build()
+}
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
@@ -35,28 +72,59 @@ async fn main() -> color_eyre::Result<()> {
         MQTTSender::empty()
     };
 
+    let mut secrets = BTreeMap::new();
+    for (name, source) in config.secrets.iter() {
+        secrets.insert(name.clone(), source.resolve()?);
+    }
+    let masker = SecretMasker::new(secrets.values().cloned());
+
     let port = port_from_config(&config.port).await?;
-    let port = SerialLogger::new(port);
-    let port = wrap_mqtt_serial_log(port, mqtt_sender.clone()).await?;
-    let (port, rawlog_target) = wrap_raw_serial_log(port).await?;
+    let port = SerialLogger::new(port, masker.clone());
+    let port = wrap_mqtt_serial_log(port, mqtt_sender.clone(), masker.clone()).await?;
+    let (port, rawlog_target) = wrap_raw_serial_log(port, masker).await?;
     let mut p = SwitchExpect::new(port, None);
 
-    let mut smb = StateMachineBuilder::new();
-    smb.load_builtin_state_files()?;
-    for id in config.active_states.iter() {
-        smb.activate_state_file(id)?;
+    let sm = build_state_machine(&config, &config.active_states)?;
+    let mut profiles = Vec::new();
+    for profile in config.profiles.iter() {
+        let active_states = profile.active_states.as_ref().unwrap_or(&config.active_states);
+        profiles.push(Profile {
+            name: profile.name.clone(),
+            rules: ProfileRules::new(
+                profile.vendor.clone(),
+                profile.model.as_deref(),
+                profile.serial_numbers.clone(),
+            )
+            .wrap_err_with(|| format!("invalid rules in profile {}", profile.name))?,
+            skip: profile.skip,
+            state_machine: Arc::new(
+                build_state_machine(&config, active_states)
+                    .wrap_err_with(|| format!("unable to build the state machine of profile {}", profile.name))?,
+            ),
+        });
     }
-    let sm = smb.build()?;
+    // A profile is selected in whatever state the default machine is in at that point.
+    check_profiles(&sm, &profiles)?;
 
     let mut job = ActiveJob::create(
         mqtt_sender.clone(),
         config.log_dir.clone(),
         tracing_target,
         rawlog_target,
-        sm,
         config.job_config.clone(),
+        secrets,
     );
-    job.reset().await?;
+    job.set_state_machine(StateMachineManifest {
+        fingerprint: sm.fingerprint().to_string(),
+        active_files: sm.active_files().to_vec(),
+        profiles: profiles
+            .iter()
+            .map(|p| (p.name.clone(), p.state_machine.fingerprint().to_string()))
+            .collect(),
+    })
+    .await?;
+    let mut runner = StateMachineRunner::new(Arc::new(sm)).with_profiles(profiles);
+    runner.reset(&mut job).await?;
 
     loop {
         tokio::select! {
@@ -64,7 +132,7 @@ async fn main() -> color_eyre::Result<()> {
                 if let Some(cmd) = msg {
                     match cmd {
                         JobCommand::ResetJob => {
-                            job.reset().await?;
+                            runner.reset(&mut job).await?;
                         },
                         JobCommand::RestartAngel => {
                             job.flag_restart().await?;
@@ -77,7 +145,7 @@ async fn main() -> color_eyre::Result<()> {
                     return Err(eyre!("MQTT broken."));
                 }
             },
-            r = job.step(&mut p) => {
+            r = runner.step(&mut job, &mut p) => {
                 r?;
             },
         }
@@ -1,3 +1,4 @@
+use crate::secrets::SecretMasker;
 use cthulhu_common::status::{JobCommand, JobUpdate};
 use cthulhu_config::angel::AngelHeavenConfig;
 use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
@@ -57,6 +58,7 @@ impl MQTTSender {
 pub async fn wrap_mqtt_serial_log<IO: 'static + AsyncRead + AsyncWrite + Unpin + Send + Sync>(
     inp: IO,
     mqtt_sender: MQTTSender,
+    masker: SecretMasker,
 ) -> color_eyre::Result<impl 'static + AsyncRead + AsyncWrite + Unpin + Send + Sync> {
     let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
     tokio::spawn(async move {
@@ -67,8 +69,12 @@ pub async fn wrap_mqtt_serial_log<IO: 'static + AsyncRead + AsyncWrite + Unpin +
             }
         }
     });
+    let mut masked = masker.stream();
     Ok(InspectReader::new(inp, move |d| {
-        let _ = sender.send(d.to_vec());
+        let d = masked.push(d);
+        if !d.is_empty() {
+            let _ = sender.send(d);
+        }
     }))
 }
 
@@ -83,32 +89,26 @@ pub async fn create_mqtt_sender_from_config(
     tx: Sender<JobCommand>,
 ) -> color_eyre::Result<MQTTSender> {
     let (mqtt_client, mut mqtt_eventloop) =
-        rumqttc::AsyncClient::new(mqtt_options_from_config(&hconfig).await?, 10);
+        rumqttc::AsyncClient::new(mqtt_options_from_config(hconfig).await?, 10);
 
     mqtt_client
         .subscribe(format!("cthulhu/{}/command", hconfig.id), QoS::AtLeastOnce)
         .await?;
     mqtt_client
-        .subscribe(format!("cthulhu/command"), QoS::AtLeastOnce)
+        .subscribe("cthulhu/command", QoS::AtLeastOnce)
         .await?;
 
     let id = hconfig.id.clone();
     tokio::spawn(async move {
         loop {
             let r = mqtt_eventloop.poll().await;
-            if let Ok(notification) = r {
-                match notification {
-                    Event::Incoming(Incoming::Publish(payload)) => {
-                        if payload.topic == format!("cthulhu/{}/command", id) || payload.topic == "cthulhu/command" {
-                            let command: JobCommand =
-                                serde_json::from_slice(&payload.payload).unwrap();
-                            info!("Received command: {command:?}");
-                            if let Err(e) = tx.send(command).await {
-                                warn!("Unable to TX command: {e:?}");
-                            }
-                        }
-                    }
-                    _ => {}
+            if let Ok(Event::Incoming(Incoming::Publish(payload))) = r
+                && (payload.topic == format!("cthulhu/{}/command", id) || payload.topic == "cthulhu/command")
+            {
+                let command: JobCommand = serde_json::from_slice(&payload.payload).unwrap();
+                info!("Received command: {command:?}");
+                if let Err(e) = tx.send(command).await {
+                    warn!("Unable to TX command: {e:?}");
                 }
             }
         }
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+const MASK: &[u8] = b"***";
+
+/// Replaces secret values with `***` before data ends up in a log.
+#[derive(Clone, Default)]
+pub struct SecretMasker {
+    secrets: Arc<Vec<Vec<u8>>>,
+}
+
+impl SecretMasker {
+    pub fn new<I: IntoIterator<Item = String>>(secrets: I) -> Self {
+        let mut secrets: Vec<Vec<u8>> = secrets
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .map(String::into_bytes)
+            .collect();
+        // Prefer the longest match if one secret starts with another.
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        Self {
+            secrets: Arc::new(secrets),
+        }
+    }
+
+    pub fn mask(&self, data: &[u8]) -> Vec<u8> {
+        if self.secrets.is_empty() {
+            return data.to_vec();
+        }
+        let mut out = Vec::with_capacity(data.len());
+        let mut i = 0;
+        while i < data.len() {
+            if let Some(s) = self.secrets.iter().find(|s| data[i..].starts_with(s)) {
+                out.extend_from_slice(MASK);
+                i += s.len();
+            } else {
+                out.push(data[i]);
+                i += 1;
+            }
+        }
+        out
+    }
+
+    pub fn mask_str(&self, data: &str) -> String {
+        if self.secrets.is_empty() {
+            return data.to_string();
+        }
+        String::from_utf8_lossy(&self.mask(data.as_bytes())).into_owned()
+    }
+
+    /// Length of the longest tail of `data` that could be the start of a secret.
+    fn partial_tail(&self, data: &[u8]) -> usize {
+        self.secrets
+            .iter()
+            .flat_map(|s| (1..s.len().min(data.len() + 1)).filter(|&n| data.ends_with(&s[..n])))
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn stream(&self) -> MaskingStream {
+        MaskingStream {
+            masker: self.clone(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Masks data that arrives in chunks. A chunk ending in what could be the start of a
+/// secret is held back until the next chunk shows whether it is one.
+pub struct MaskingStream {
+    masker: SecretMasker,
+    pending: Vec<u8>,
+}
+
+impl MaskingStream {
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.masker.secrets.is_empty() {
+            return data.to_vec();
+        }
+        self.pending.extend_from_slice(data);
+        let mut masked = self.masker.mask(&self.pending);
+        let hold = self.masker.partial_tail(&masked);
+        self.pending = masked.split_off(masked.len() - hold);
+        masked
+    }
+}
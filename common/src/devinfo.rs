@@ -1,4 +1,6 @@
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -27,9 +29,34 @@ pub enum DeviceInformation {
     BadFlashBlock,
     SoftwareUpdatePerformed,
     DidNotWipe,
+    TimedOut,
+    /// Text to send could not be filled in, for example because a config value is missing.
+    TemplateFailed,
+    /// A component of the device, like a line card, PSU or optic, by column.
+    InventoryItem(BTreeMap<String, String>),
 }
 
 impl DeviceInformation {
+    /// The kind and value of this item, if it carries a value.
+    pub fn value(&self) -> Option<(DeviceInformationKind, &str)> {
+        match self {
+            DeviceInformation::SerialNumber(v) => Some((DeviceInformationKind::SerialNumber, v)),
+            DeviceInformation::MacAddress(v) => Some((DeviceInformationKind::MacAddress, v)),
+            DeviceInformation::SoftwareVersion(v) => Some((DeviceInformationKind::SoftwareVersion, v)),
+            DeviceInformation::BootloaderVersion(v) => Some((DeviceInformationKind::BootloaderVersion, v)),
+            DeviceInformation::Model(v) => Some((DeviceInformationKind::Model, v)),
+            DeviceInformation::Vendor(v) => Some((DeviceInformationKind::Vendor, v)),
+            _ => None,
+        }
+    }
+
+    /// Whether recording this item replaces `other`. A job keeps one item per variant,
+    /// except for inventory items, which are kept one per component.
+    pub fn replaces(&self, other: &DeviceInformation) -> bool {
+        !matches!(self, DeviceInformation::InventoryItem(_))
+            && std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
     pub fn get_type(&self) -> DeviceInformationType {
         match self {
             DeviceInformation::SerialNumber(_) => DeviceInformationType::Info,
@@ -56,13 +83,65 @@ impl DeviceInformation {
             DeviceInformation::BootloaderVersion(_) => DeviceInformationType::Info,
             DeviceInformation::SoftwareUpdatePerformed => DeviceInformationType::Warning,
             DeviceInformation::DidNotWipe => DeviceInformationType::Error,
+            DeviceInformation::TimedOut => DeviceInformationType::Error,
+            DeviceInformation::TemplateFailed => DeviceInformationType::Error,
+            DeviceInformation::InventoryItem(_) => DeviceInformationType::Info,
         }
     }
 }
 
 impl Display for DeviceInformation {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        Debug::fmt(&self, f)
+        match self {
+            DeviceInformation::InventoryItem(columns) => {
+                write!(f, "InventoryItem:")?;
+                for (i, (column, value)) in columns.iter().filter(|(_, v)| !v.is_empty()).enumerate() {
+                    write!(f, "{} {column}: {value}", if i == 0 { "" } else { "," })?;
+                }
+                Ok(())
+            }
+            _ => Debug::fmt(&self, f),
+        }
+    }
+}
+
+/// The kinds of device information that carry a value.
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum DeviceInformationKind {
+    SerialNumber,
+    MacAddress,
+    SoftwareVersion,
+    BootloaderVersion,
+    Model,
+    Vendor,
+}
+
+impl DeviceInformationKind {
+    pub fn with_value(self, value: String) -> DeviceInformation {
+        match self {
+            DeviceInformationKind::SerialNumber => DeviceInformation::SerialNumber(value),
+            DeviceInformationKind::MacAddress => DeviceInformation::MacAddress(value),
+            DeviceInformationKind::SoftwareVersion => DeviceInformation::SoftwareVersion(value),
+            DeviceInformationKind::BootloaderVersion => DeviceInformation::BootloaderVersion(value),
+            DeviceInformationKind::Model => DeviceInformation::Model(value),
+            DeviceInformationKind::Vendor => DeviceInformation::Vendor(value),
+        }
+    }
+}
+
+impl FromStr for DeviceInformationKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "SerialNumber" => Ok(DeviceInformationKind::SerialNumber),
+            "MacAddress" => Ok(DeviceInformationKind::MacAddress),
+            "SoftwareVersion" => Ok(DeviceInformationKind::SoftwareVersion),
+            "BootloaderVersion" => Ok(DeviceInformationKind::BootloaderVersion),
+            "Model" => Ok(DeviceInformationKind::Model),
+            "Vendor" => Ok(DeviceInformationKind::Vendor),
+            _ => Err(format!("unknown device information kind: {s}")),
+        }
     }
 }
 
@@ -1,15 +1,11 @@
 use crate::devinfo::{DeviceInformation, DeviceInformationType};
 use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::Display;
 use std::ops::Add;
 use crate::status::JobUpdate;
 
-fn variant_eq<T>(a: &T, b: &T) -> bool {
-    std::mem::discriminant(a) == std::mem::discriminant(b)
-}
-
 /// Current and historical data of a job.
 #[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct JobData {
@@ -22,6 +18,66 @@ pub struct JobData {
     pub state_history: Vec<(DateTime<Utc>, String)>,
     /// List of device information
     pub info_items: HashSet<DeviceInformation>,
+    /// Variables set by the state machine
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    /// Readable information about the current stage
+    #[serde(default)]
+    pub stage: StageMetadata,
+    /// The state machine the angel built, kept across jobs
+    #[serde(default)]
+    pub state_machine: Option<Box<StateMachineManifest>>,
+}
+
+/// Identifies the state machine an angel built, to spot angels running different versions.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateMachineManifest {
+    /// Content hash of the merged state machine, with the scripts and templates it can use.
+    pub fingerprint: String,
+    /// Ids of the active state files, in merge order.
+    pub active_files: Vec<String>,
+    /// Fingerprints of the state machines of per device profiles, by profile name.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, String>,
+}
+
+impl StateMachineManifest {
+    /// The start of the fingerprint, enough to tell versions apart at a glance.
+    pub fn short_fingerprint(&self) -> &str {
+        self.fingerprint.get(..12).unwrap_or(&self.fingerprint)
+    }
+}
+
+/// Coarse phase of a job, shown to operators.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobPhase {
+    Detect,
+    Wipe,
+    Verify,
+    Provision,
+    Poweroff,
+}
+
+impl Display for JobPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobPhase::Detect => write!(f, "Detect"),
+            JobPhase::Wipe => write!(f, "Wipe"),
+            JobPhase::Verify => write!(f, "Verify"),
+            JobPhase::Provision => write!(f, "Provision"),
+            JobPhase::Poweroff => write!(f, "Power off"),
+        }
+    }
+}
+
+/// Readable information about a stage, taken from the state machine.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StageMetadata {
+    pub description: Option<String>,
+    pub phase: Option<JobPhase>,
+    /// How far along the job is, in percent.
+    pub progress: Option<u8>,
 }
 
 impl JobData {
@@ -32,6 +88,9 @@ impl JobData {
             job_ended: None,
             state_history: Vec::new(),
             info_items: HashSet::new(),
+            variables: BTreeMap::new(),
+            stage: StageMetadata::default(),
+            state_machine: None,
         }
     }
 
@@ -40,17 +99,26 @@ impl JobData {
         self.job_ended = None;
         self.state_history = Vec::new();
         self.info_items = HashSet::new();
+        self.variables = BTreeMap::new();
+        self.stage = StageMetadata::default();
     }
 
+    /// Add an item, replacing an earlier one of the same variant. Inventory items are
+    /// all kept, one per component.
     pub fn add_info_item(&mut self, i: DeviceInformation) {
-        self.info_items.retain(|x| !variant_eq(x, &i));
+        self.info_items.retain(|x| !i.replaces(x));
         self.info_items.insert(i);
     }
 
     pub fn update(&mut self, update: JobUpdate) {
         match update {
-            JobUpdate::JobStageTransition(d, s) => {
+            JobUpdate::JobStageTransition(d, s, m) => {
                 self.state_history.push((d, s));
+                // Progress never goes back during a job, even in stages without any.
+                self.stage = StageMetadata {
+                    progress: self.stage.progress.max(m.progress),
+                    ..m
+                };
             }
             JobUpdate::JobStart(d) => {
                 self.reset();
@@ -65,6 +133,12 @@ impl JobData {
             JobUpdate::JobFullData(d) => {
                 *self = d;
             }
+            JobUpdate::JobVariable(name, value) => {
+                self.variables.insert(name, value);
+            }
+            JobUpdate::StateMachine(m) => {
+                self.state_machine = Some(Box::new(m));
+            }
         }
     }
 
@@ -72,8 +146,16 @@ impl JobData {
         self.state_history.last().map(|(_, s)| s.as_str())
     }
 
+    /// The description of the current stage, or its name if it has none.
+    pub fn get_current_stage_description(&self) -> Option<&str> {
+        self.stage
+            .description
+            .as_deref()
+            .or_else(|| self.get_current_stage())
+    }
+
     pub fn get_last_updated(&self) -> Option<DateTime<Utc>> {
-        self.state_history.last().map(|(s, _)| s.clone())
+        self.state_history.last().map(|(s, _)| *s)
     }
 
     pub fn get_max_information_type(&self) -> DeviceInformationType {
@@ -133,22 +215,17 @@ pub enum JobStatus {
 
 impl JobStatus {
     pub fn is_idle(&self) -> bool {
-        match self {
-            JobStatus::Idle => true,
-            JobStatus::FinishSuccess => true,
-            JobStatus::FinishWarning => true,
-            JobStatus::FinishError => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            JobStatus::Idle | JobStatus::FinishSuccess | JobStatus::FinishWarning | JobStatus::FinishError
+        )
     }
 
     pub fn is_finished(&self) -> bool {
-        match self {
-            JobStatus::FinishSuccess => true,
-            JobStatus::FinishWarning => true,
-            JobStatus::FinishError => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            JobStatus::FinishSuccess | JobStatus::FinishWarning | JobStatus::FinishError
+        )
     }
 }
 
@@ -165,3 +242,63 @@ impl Display for JobStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_items() {
+        let item = |name: &str| {
+            DeviceInformation::InventoryItem(BTreeMap::from([("Item".to_string(), name.to_string())]))
+        };
+        let mut data = JobData::with_label("S1");
+        for update in [
+            JobUpdate::JobNewInfoItem(DeviceInformation::Model("ex2200".to_string())),
+            JobUpdate::JobNewInfoItem(item("Chassis")),
+            JobUpdate::JobNewInfoItem(item("PSU 0")),
+            JobUpdate::JobNewInfoItem(DeviceInformation::Model("ex3300".to_string())),
+        ] {
+            data.update(update);
+        }
+        assert_eq!(
+            data.info_items,
+            HashSet::from([
+                DeviceInformation::Model("ex3300".to_string()),
+                item("Chassis"),
+                item("PSU 0"),
+            ])
+        );
+    }
+
+    #[test]
+    fn stage_metadata() {
+        let stage = |description: Option<&str>, phase, progress| StageMetadata {
+            description: description.map(str::to_string),
+            phase,
+            progress,
+        };
+        let mut data = JobData::with_label("S1");
+        data.update(JobUpdate::JobStageTransition(
+            Utc::now(),
+            "JunosVersionOutput".to_string(),
+            stage(Some("Reading the version"), Some(JobPhase::Verify), Some(65)),
+        ));
+        assert_eq!(data.get_current_stage_description(), Some("Reading the version"));
+
+        data.update(JobUpdate::JobStageTransition(
+            Utc::now(),
+            "HookJunosCLI".to_string(),
+            stage(None, None, None),
+        ));
+        assert_eq!(data.stage, stage(None, None, Some(65)));
+        assert_eq!(data.get_current_stage_description(), Some("HookJunosCLI"));
+
+        data.update(JobUpdate::JobStageTransition(
+            Utc::now(),
+            "JunosRecovery".to_string(),
+            stage(None, Some(JobPhase::Wipe), Some(20)),
+        ));
+        assert_eq!(data.stage, stage(None, Some(JobPhase::Wipe), Some(65)));
+    }
+}
@@ -1,15 +1,20 @@
 use crate::devinfo::DeviceInformation;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use crate::job::JobData;
+use crate::job::{JobData, StageMetadata, StateMachineManifest};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum JobUpdate {
-    JobStageTransition(DateTime<Utc>, String),
+    /// The job entered a state, described by the metadata.
+    JobStageTransition(DateTime<Utc>, String, StageMetadata),
     JobStart(DateTime<Utc>),
     JobEnd(DateTime<Utc>),
     JobNewInfoItem(DeviceInformation),
     JobFullData(JobData),
+    /// A job variable was set to a new value.
+    JobVariable(String, String),
+    /// Sent when the angel starts, describing the state machine it built.
+    StateMachine(StateMachineManifest),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use color_eyre::eyre::WrapErr;
 use serde::Deserialize;
 use std::path::PathBuf;
 use crate::LoadableConfig;
@@ -9,9 +10,18 @@ pub struct AngelConfig {
     pub log_dir: Option<PathBuf>,
     #[serde(default = "default_active_states")]
     pub active_states: Vec<String>,
+    /// Extra directories to load state files and scripts from, after the builtin ones.
+    #[serde(default)]
+    pub state_dirs: Vec<PathBuf>,
+    /// Per device overrides of `active_states`; the first matching profile is used.
+    #[serde(rename = "Profile", default)]
+    pub profiles: Vec<AngelProfileConfig>,
 
     #[serde(rename = "JobConfig", default)]
     pub job_config: BTreeMap<String, String>,
+    /// Values for `{{secret.name}}` in state files. They are masked in all logs.
+    #[serde(rename = "Secrets", default)]
+    pub secrets: BTreeMap<String, SecretSource>,
 
     #[serde(flatten)]
     pub port: AngelPortConfig,
@@ -25,6 +35,45 @@ fn default_active_states() -> Vec<String> {
 
 impl LoadableConfig for AngelConfig {}
 
+/// Selected once `SwitchDetect` has identified the device. Every rule that is set has to hold.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AngelProfileConfig {
+    pub name: String,
+    /// Matched case-insensitively.
+    pub vendor: Option<String>,
+    /// A regex for the model.
+    pub model: Option<String>,
+    #[serde(default)]
+    pub serial_numbers: Vec<String>,
+    /// Defaults to the global `active_states`.
+    pub active_states: Option<Vec<String>>,
+    /// Record `DidNotWipe` and end the job instead.
+    #[serde(default)]
+    pub skip: bool,
+}
+
+/// Where to read a secret from, e.g. `root_password = { env = "ROOT_PASSWORD" }`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SecretSource {
+    Env { env: String },
+    /// Read from a file, without the trailing newline.
+    File { file: PathBuf },
+}
+
+impl SecretSource {
+    pub fn resolve(&self) -> color_eyre::Result<String> {
+        match self {
+            SecretSource::Env { env } => std::env::var(env)
+                .wrap_err_with(|| format!("unable to read secret from environment variable {env}")),
+            SecretSource::File { file } => Ok(std::fs::read_to_string(file)
+                .wrap_err_with(|| format!("unable to read secret from {file:?}"))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string()),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct AngelHeavenConfig {
     pub id: String,
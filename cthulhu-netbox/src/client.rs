@@ -32,7 +32,7 @@ impl NetboxClient {
             let u = Url::parse_with_params(&u, &[("serial", sn)])?;
             let resp = self.http_client.get(u).send().await?;
             if resp.status().is_server_error() && retries < 3 {
-                retries = retries + 1;
+                retries += 1;
                 warn!("Error fetching device {sn}, retry...");
                 continue;
             }
@@ -55,7 +55,7 @@ impl NetboxClient {
                 .json(&body)
                 .send().await?;
             if resp.status().is_server_error() && retries < 3 {
-                retries = retries + 1;
+                retries += 1;
                 warn!("Error updating device {id}, retry...");
             } else {
                 let _resp = resp.error_for_status()?;
@@ -80,7 +80,7 @@ impl NetboxClient {
                 .json(&body)
                 .send().await?;
             if resp.status().is_server_error() && retries < 3 {
-                retries = retries + 1;
+                retries += 1;
                 warn!("Error updating device {device_id}, retry...");
             } else {
                 let _resp = resp.error_for_status()?;
@@ -43,32 +43,29 @@ async fn main() -> color_eyre::Result<()> {
     info!("Running...");
     loop {
         let notification = mqtt_eventloop.poll().await?;
-        match notification {
-            Event::Incoming(Incoming::Publish(publish)) => {
-                if let Some(caps) = update_re.captures(&publish.topic) {
-                    let label = (&caps["port_label"]).to_string();
-                    let update: JobUpdate = serde_json::from_slice(&publish.payload)?;
-                    info!("Received update for {}.", label);
-
-                    let data = port_map
-                        .entry(label.clone())
-                        .or_insert_with(|| JobData::with_label(&label));
-                    data.update(update.clone());
-
-                    if let JobUpdate::JobEnd(_) = update
-                        && let Some(sn) = get_sn_from_job(data)
-                    {
-                        info!(
-                            "Device with serial number {} on port {} has finished!",
-                            sn, label
-                        );
-                        if let Err(e) = update_device(&nb_client, &config.netbox, &sn, data).await {
-                            warn!("Unable to update device with ID {}: {}", sn, e);
-                        }
-                    }
+        if let Event::Incoming(Incoming::Publish(publish)) = notification
+            && let Some(caps) = update_re.captures(&publish.topic)
+        {
+            let label = caps["port_label"].to_string();
+            let update: JobUpdate = serde_json::from_slice(&publish.payload)?;
+            info!("Received update for {}.", label);
+
+            let data = port_map
+                .entry(label.clone())
+                .or_insert_with(|| JobData::with_label(&label));
+            data.update(update.clone());
+
+            if let JobUpdate::JobEnd(_) = update
+                && let Some(sn) = get_sn_from_job(data)
+            {
+                info!(
+                    "Device with serial number {} on port {} has finished!",
+                    sn, label
+                );
+                if let Err(e) = update_device(&nb_client, &config.netbox, &sn, data).await {
+                    warn!("Unable to update device with ID {}: {}", sn, e);
                 }
             }
-            _ => {}
         }
     }
 }
@@ -79,7 +76,7 @@ async fn update_device(
     sn: &str,
     data: &JobData,
 ) -> color_eyre::Result<()> {
-    let device_id = nb_client.get_device_id_by_serial(&sn).await?;
+    let device_id = nb_client.get_device_id_by_serial(sn).await?;
 
     if data.get_max_information_type() != DeviceInformationType::Error {
         nb_client
@@ -1,3 +1,7 @@
+// The Host extractor is deprecated without a replacement yet, see
+// https://github.com/tokio-rs/axum/issues/3442
+#![allow(deprecated)]
+
 use crate::state::AppStateHandle;
 use askama::Template;
 use axum::Router;
@@ -98,16 +102,14 @@ async fn get_stage2(
 
 async fn get_swi(State(state): State<AppStateHandle>, Path(path): Path<String>) -> Response {
     for os_mapping in state.os_mappings.iter() {
-        if os_mapping.vendor == "Arista" {
-            if os_mapping.os_image.ends_with(&path) {
-                let f = File::open(&os_mapping.os_image).await;
-                let Ok(f) = f else {
-                    return (StatusCode::NOT_FOUND, "Unable to find SWI").into_response();
-                };
-                let reader = ReaderStream::new(f);
-                let body = Body::from_stream(reader);
-                return (StatusCode::OK, body).into_response();
-            }
+        if os_mapping.vendor == "Arista" && os_mapping.os_image.ends_with(&path) {
+            let f = File::open(&os_mapping.os_image).await;
+            let Ok(f) = f else {
+                return (StatusCode::NOT_FOUND, "Unable to find SWI").into_response();
+            };
+            let reader = ReaderStream::new(f);
+            let body = Body::from_stream(reader);
+            return (StatusCode::OK, body).into_response();
         }
     }
 
@@ -27,7 +27,7 @@ async fn main() -> color_eyre::Result<()> {
 
     // Initialize logging
     let max_log_level =
-        Level::from_str(&(config.log_level.as_ref().unwrap_or(&"info".to_string())))?;
+        Level::from_str(config.log_level.as_ref().unwrap_or(&"info".to_string()))?;
     let stdsub =
         tracing_subscriber::fmt::layer().with_filter(LevelFilter::from_level(max_log_level));
     let subscriber = Registry::default().with(stdsub);
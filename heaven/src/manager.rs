@@ -1,5 +1,5 @@
-use crate::mqtt::{BroadcastSender, MQTTBroadcast, MQTTSender};
-use cthulhu_common::status::{JobCommand, JobUpdate};
+use crate::mqtt::{BroadcastSender, MQTTBroadcast};
+use cthulhu_common::status::JobUpdate;
 use serde::Serialize;
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -51,10 +51,7 @@ impl JobManager {
 
     pub async fn get_ports(&self) -> Vec<PortManagerEntry> {
         let r = self.inner.read().await;
-        r.ports
-            .iter()
-            .cloned()
-            .collect()
+        r.ports.to_vec()
     }
 
     pub async fn get_port(&self, label: &str) -> Option<PortManagerEntry> {
@@ -75,11 +72,8 @@ impl JobManager {
         let mut inner = self.inner.write().await;
         let existing = inner.get_port_mut(port_label);
 
-        match &update {
-            JobUpdate::JobStart(_) => {
-                existing.log_buffer = Vec::new();
-            }
-            _ => {}
+        if let JobUpdate::JobStart(_) = &update {
+            existing.log_buffer = Vec::new();
         }
 
         existing.data.update(update);
@@ -88,15 +82,9 @@ impl JobManager {
     }
 }
 
-pub async fn manager_main(
-    broadcast: BroadcastSender,
-    sender: MQTTSender,
-    manager: JobManager,
-) -> color_eyre::Result<()> {
+pub async fn manager_main(broadcast: BroadcastSender, manager: JobManager) -> color_eyre::Result<()> {
     let mut receiver = broadcast.subscribe();
 
-    sender.broadcast_command(JobCommand::GetJobData).await?;
-    
     loop {
         let msg = receiver.recv().await;
         match msg {
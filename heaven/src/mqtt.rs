@@ -32,17 +32,23 @@ pub async fn mqtt_main(
         match r {
             Event::Incoming(Incoming::Publish(publish)) => {
                 if let Some(caps) = update_re.captures(&publish.topic) {
-                    let label = (&caps["port_label"]).to_string();
+                    let label = caps["port_label"].to_string();
                     let update: JobUpdate = serde_json::from_slice(&publish.payload)?;
                     info!("Received update for port {label}: {update:?}");
                     let _ = sender.send(MQTTBroadcast::JobUpdate { label, update });
                 }
                 if let Some(caps) = serial_re.captures(&publish.topic) {
-                    let label = (&caps["port_label"]).to_string();
+                    let label = caps["port_label"].to_string();
                     let data = publish.payload.to_vec();
                     let _ = sender.send(MQTTBroadcast::SerialData { label, data });
                 }
             }
+            Event::Incoming(Incoming::ConnAck(_)) => {
+                // Ask for everything the angels know, so a restarted heaven does not miss the
+                // state machine manifests they only send on startup.
+                let data = serde_json::to_vec(&JobCommand::GetJobData)?;
+                mqtt_client.try_publish("cthulhu/command", QoS::AtMostOnce, false, data)?;
+            }
             _ => {
                 trace!("Ignoring unknown event.");
             }
@@ -72,7 +78,7 @@ impl MQTTSender {
     pub async fn broadcast_command(&self, command: JobCommand) -> color_eyre::Result<()> {
         let data = serde_json::to_vec(&command)?;
         self.client
-            .publish(format!("cthulhu/command"), QoS::AtMostOnce, false, data)
+            .publish("cthulhu/command", QoS::AtMostOnce, false, data)
             .await?;
         Ok(())
     }
@@ -1,7 +1,8 @@
 use chrono::{DateTime, Utc};
 use chrono_humanize::HumanTime;
 use cthulhu_common::devinfo::DeviceInformation;
-use cthulhu_common::job::{JobData, JobStatus};
+use cthulhu_common::job::{JobData, JobStatus, StateMachineManifest};
+use crate::manager::PortManagerEntry;
 
 pub trait PortStatusExt {
     fn get_css_backgroundcolor(&self) -> String;
@@ -39,7 +40,7 @@ pub trait DateTimeAgo {
 
 impl DateTimeAgo for DateTime<Utc> {
     fn timeago(&self) -> String {
-        let v = HumanTime::from(self.clone());
+        let v = HumanTime::from(*self);
         let d = format!("{}", v);
         d
     }
@@ -47,9 +48,8 @@ impl DateTimeAgo for DateTime<Utc> {
 
 pub fn get_dev_manuf(port: &JobData) -> String {
     for i in port.info_items.iter() {
-        match i {
-            DeviceInformation::Vendor(v) => return v.clone(),
-            _ => {}
+        if let DeviceInformation::Vendor(v) = i {
+            return v.clone();
         }
     }
     "UNKN".to_string()
@@ -57,9 +57,8 @@ pub fn get_dev_manuf(port: &JobData) -> String {
 
 pub fn get_dev_model(port: &JobData) -> String {
     for i in port.info_items.iter() {
-        match i {
-            DeviceInformation::Model(v) => return v.clone(),
-            _ => {}
+        if let DeviceInformation::Model(v) = i {
+            return v.clone();
         }
     }
     "UNKN".to_string()
@@ -67,10 +66,36 @@ pub fn get_dev_model(port: &JobData) -> String {
 
 pub fn get_dev_sn(port: &JobData) -> String {
     for i in port.info_items.iter() {
-        match i {
-            DeviceInformation::SerialNumber(v) => return v.clone(),
-            _ => {}
+        if let DeviceInformation::SerialNumber(v) = i {
+            return v.clone();
         }
     }
     "UNKN".to_string()
-}
\ No newline at end of file
+}
+
+/// The state machine most ports run. Ports running another one were left behind by a partial redeploy.
+/// All ports of this heaven are compared, whichever angel serves them, so all angels are expected to
+/// run the same state files.
+pub fn common_state_machine(ports: &[PortManagerEntry]) -> Option<StateMachineManifest> {
+    let mut counts: Vec<(&StateMachineManifest, usize)> = Vec::new();
+    for m in ports.iter().filter_map(|p| p.data.state_machine.as_deref()) {
+        match counts.iter_mut().find(|(c, _)| *c == m) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((m, 1)),
+        }
+    }
+    counts.into_iter().max_by_key(|(_, n)| *n).map(|(m, _)| m.clone())
+}
+
+/// Whether the port runs a different state machine than `common`.
+pub fn runs_other_state_machine(port: &JobData, common: Option<&StateMachineManifest>) -> bool {
+    port.state_machine.is_some() && port.state_machine.as_deref() != common
+}
+
+pub fn state_machine_title(manifest: &StateMachineManifest) -> String {
+    let mut title = format!("{}\nActive: {}", manifest.fingerprint, manifest.active_files.join(", "));
+    for (name, fingerprint) in manifest.profiles.iter() {
+        title.push_str(&format!("\nProfile {name}: {fingerprint}"));
+    }
+    title
+}
@@ -45,6 +45,7 @@ pub async fn web_main(
         .route("/port/{port_label}/", get(pages::port::port))
         .route("/port/{port_label}/header.html", get(pages::port::header))
         .route("/port/{port_label}/devinfo.html", get(pages::port::footer))
+        .route("/port/{port_label}/job.json", get(pages::port::job_json))
         .route("/port/{port_label}/abort", get(abort))
         .route("/port/{port_label}/serial", get(serial_handler))
         .route("/assets/{*path}", get(static_path))
@@ -25,8 +25,18 @@ pub async fn port_status(State(state): State<WebState>) -> Markup {
 
     let column_height = 8usize.min(ports.len());
     let total_columns = ((ports.len() as f64) / (column_height as f64)).ceil() as usize;
+    let common = common_state_machine(&ports);
+    let mismatched = ports
+        .iter()
+        .filter(|p| runs_other_state_machine(&p.data, common.as_ref()))
+        .count();
 
     html! {
+        @if mismatched > 0 {
+            p class="warning" {
+                "⚠️ " (mismatched) " port(s) run a different state machine than the others, redeploy their angels."
+            }
+        }
         table class="outer" {
             @for i in 0..column_height {
                 tr {
@@ -49,6 +59,11 @@ pub async fn port_status(State(state): State<WebState>) -> Markup {
                                         }
                                         td {
                                             (port.data.get_status())
+                                            @if runs_other_state_machine(&port.data, common.as_ref()) {
+                                                span title=(port.data.state_machine.as_deref().map(state_machine_title).unwrap_or_default()) {
+                                                    " ⚠️"
+                                                }
+                                            }
                                         }
                                     }
                                     tr {
@@ -63,8 +78,18 @@ pub async fn port_status(State(state): State<WebState>) -> Markup {
                                         }
                                     }
                                     tr {
-                                        td colspan="3" {
-                                            (port.data.get_current_stage().unwrap_or("UNKN"))
+                                        td colspan="3" title=(port.data.get_current_stage().unwrap_or("UNKN")) {
+                                            @if let Some(phase) = port.data.stage.phase {
+                                                b { (phase) ": " }
+                                            }
+                                            (port.data.get_current_stage_description().unwrap_or("UNKN"))
+                                        }
+                                    }
+                                    @if let Some(progress) = port.data.stage.progress {
+                                        tr {
+                                            td colspan="3" {
+                                                progress class="stage-progress" value=(progress) max="100" {}
+                                            }
                                         }
                                     }
                                     tr {
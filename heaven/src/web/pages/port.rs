@@ -1,9 +1,13 @@
 use crate::web::WebState;
-use crate::web::helpers::{DateTimeAgo, PortStatusExt};
+use crate::web::helpers::{
+    DateTimeAgo, PortStatusExt, common_state_machine, runs_other_state_machine, state_machine_title,
+};
 use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
+use axum::Json;
 use chrono::Utc;
+use cthulhu_common::job::JobData;
 use maud::{DOCTYPE, Markup, html};
 
 pub async fn header(
@@ -17,6 +21,7 @@ pub async fn header(
     };
 
     let ps = format!("{:?}", port.data.get_status());
+    let common = common_state_machine(&state.manager.get_ports().await);
 
     Ok(html! {
         table {
@@ -31,8 +36,15 @@ pub async fn header(
                 td {
                     "Current stage:"
                 }
-                td {
-                    (port.data.get_current_stage().unwrap_or("UNKN"))
+                td title=(port.data.get_current_stage().unwrap_or("UNKN")) {
+                    @if let Some(phase) = port.data.stage.phase {
+                        b { (phase) ": " }
+                    }
+                    (port.data.get_current_stage_description().unwrap_or("UNKN"))
+                    @if let Some(progress) = port.data.stage.progress {
+                        " "
+                        progress value=(progress) max="100" {}
+                    }
                 }
                 td {
                     "Current status:"
@@ -53,6 +65,23 @@ pub async fn header(
                     (port.data.get_last_updated().unwrap_or(Utc::now()).timeago())
                 }
             }
+            tr {
+                td {
+                    "State machine:"
+                }
+                @if let Some(manifest) = port.data.state_machine.as_deref() {
+                    td title=(state_machine_title(manifest)) {
+                        (manifest.short_fingerprint())
+                        @if runs_other_state_machine(&port.data, common.as_ref()) {
+                            " ⚠️ differs from the other ports"
+                        }
+                    }
+                } @else {
+                    td {
+                        "UNKN"
+                    }
+                }
+            }
             tr {
                 td {
                     "Controls:"
@@ -93,6 +122,18 @@ pub async fn footer(
                         }
                     }
                 }
+                @if !port.data.variables.is_empty() {
+                    td {
+                        h3 { "Variables:" }
+                        ul {
+                            @for (name, value) in port.data.variables.iter() {
+                                li {
+                                    (name) " = " (value)
+                                }
+                            }
+                        }
+                    }
+                }
                 td {
                     h3 { "Stage history:" }
                     ul {
@@ -137,3 +178,14 @@ pub async fn port(
         }
     })
 }
+
+/// The job data of a port, for tools such as the state machine visualizer.
+pub async fn job_json(
+    State(state): State<WebState>,
+    Path(port_label): Path<String>,
+) -> Result<Json<JobData>, Response> {
+    match state.manager.get_port(&port_label).await {
+        Some(port) => Ok(Json(port.data)),
+        None => Err((StatusCode::NOT_FOUND, "Port not found").into_response()),
+    }
+}
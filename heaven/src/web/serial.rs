@@ -29,16 +29,13 @@ async fn serial_handle_socket(mut socket: WebSocket, state: WebState, port: Port
         .unwrap();
 
     while let Ok(message) = receiver.recv().await {
-        match message {
-            MQTTBroadcast::SerialData { label, data } => {
-                if label == port.data.label {
-                    socket
-                        .send(Message::Binary(Bytes::from(data)))
-                        .await
-                        .unwrap();
-                }
-            }
-            _ => {}
+        if let MQTTBroadcast::SerialData { label, data } = message
+            && label == port.data.label
+        {
+            socket
+                .send(Message::Binary(Bytes::from(data)))
+                .await
+                .unwrap();
         }
     }
 }